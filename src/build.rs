@@ -1,15 +1,27 @@
-use std::{collections::HashMap, ffi::OsString, fmt, fs, io::{self, BufRead, BufWriter}, path::{Path, PathBuf}, process::Command, sync::{Arc, LazyLock, Mutex}};
+use std::{collections::{BTreeMap, BTreeSet, HashMap, HashSet}, ffi::OsString, fmt, fs, io::{self, BufRead as _, BufWriter, IsTerminal as _, Write as _}, path::{Path, PathBuf}, process::{Command, ExitStatus, Stdio}, sync::{Arc, LazyLock, Mutex}};
 
 use anyhow::{anyhow, bail, Context as _};
-use minijinja::{value::Object, Value};
+use minijinja::{value::{Kwargs, Object}, Value};
+use serde::Serialize;
 
-use crate::{dockerfile::{DockerFileInstruction, DockerFileParser}, template::{self, IntoValue as _}};
+use crate::{config, context::{self, DockerIgnore}, diagnostic::RenderError, git::Git, hash, host::Host, manifest::{self, Manifest}, pty, sourcemap::{Recorder, SourceMap}, dockerfile::{DockerFileInstruction, DockerFileParser}, template::{self, IntoValue as _, Strictness}, time::DateTime, vars};
 
 #[derive(Default, Debug, Clone)]
 pub struct BernConfig {
     pub stage_dir: PathBuf,
     pub file: PathBuf,
     pub context_root: PathBuf,
+    /// Directories searched for included templates after the context root
+    pub template_path: Vec<PathBuf>,
+    /// Files merged into the `vars` template variable
+    pub vars_files: Vec<PathBuf>,
+    /// Patterns for environment variables that `bern.env` may read
+    pub allow_env: Vec<String>,
+    /// Seed for `bern.random_id`, instead of the git revision
+    pub build_id: Option<String>,
+    /// Fixed time for `now()`, in seconds since the epoch
+    pub timestamp: Option<i64>,
+    pub strictness: Strictness,
     pub docker_args: Vec<String>,
     pub docker_tags: Vec<String>,
     pub build_args: HashMap<String, String>,
@@ -20,11 +32,24 @@ pub struct BernConfig {
 #[derive(Debug, Default)]
 struct RuntimeInner {
     targets: HashMap<String, Arc<Target>>,
+    stages: Vec<Arc<Target>>,
     target: Option<Arc<Target>>,
     config: Arc<BernConfig>,
     output: Option<PathBuf>,
     build_args: HashMap<String, String>,
+    git: Option<Arc<Git>>,
+    /// `bern.host`, detected the first time it is used
+    host: Option<Value>,
+    /// Generator for `bern.random_id`, started again for each render
+    rng: Option<fastrand::Rng>,
+    source_map: Arc<SourceMap>,
+    /// Build args the template asked for, whether or not they were set
+    read_build_args: BTreeSet<String>,
     docker_tags: Vec<String>,
+    /// `--secret` values by id, from `bern.secret`
+    secrets: BTreeMap<String, String>,
+    /// `--ssh` values by id, from `bern.ssh`
+    ssh: BTreeMap<String, String>,
 }
 
 #[derive(Debug, Default)]
@@ -37,7 +62,8 @@ impl Runtime {
     }
 
     fn build_arg(&self, name: &str) -> Option<String> {
-        let inner = self.0.lock().unwrap();
+        let mut inner = self.0.lock().unwrap();
+        inner.read_build_args.insert(name.to_owned());
         if let Some(outer) = inner.config.build_args.get(name) {
             Some(outer.to_owned())
         } else {
@@ -45,10 +71,161 @@ impl Runtime {
         }
     }
 
+    fn env(&self, name: &str) -> anyhow::Result<Option<String>> {
+        let inner = self.0.lock().unwrap();
+        // Patterns were checked when the settings were loaded
+        let allowed = inner.config.allow_env.iter()
+            .any(|p| glob::Pattern::new(p).is_ok_and(|p| p.matches(name)));
+        if !allowed {
+            bail!("Environment variable {name} is not allowed, allow it with --allow-env {name} or allow-env in {}", config::CONFIG_FILE_NAME);
+        }
+
+        Ok(std::env::var(name).ok())
+    }
+
+    /// Resolve a path in the context, refusing ignored files unless asked to include them.
+    fn context_path(&self, path: &str, include_ignored: bool) -> anyhow::Result<(PathBuf, PathBuf)> {
+        let root = self.0.lock().unwrap().config.context_root.clone();
+        let (relative, full) = context::resolve(&root, path)?;
+        if !include_ignored && DockerIgnore::read(&root).is_ignored(&relative) {
+            bail!("Path {path} is excluded by .dockerignore");
+        }
+        Ok((relative, full))
+    }
+
+    fn read_file(&self, path: &str, include_ignored: bool) -> anyhow::Result<String> {
+        let (_, full) = self.context_path(path, include_ignored)?;
+        fs::read_to_string(&full).with_context(|| format!("Failed to read file: {path}"))
+    }
+
+    fn file_exists(&self, path: &str, include_ignored: bool) -> anyhow::Result<bool> {
+        let root = self.0.lock().unwrap().config.context_root.clone();
+        let (relative, full) = context::resolve(&root, path)?;
+        let ignored = !include_ignored && DockerIgnore::read(&root).is_ignored(&relative);
+        Ok(!ignored && full.exists())
+    }
+
+    fn glob(&self, pattern: &str, include_ignored: bool) -> anyhow::Result<Vec<String>> {
+        let root = self.0.lock().unwrap().config.context_root.clone();
+        let ignore = if include_ignored { DockerIgnore::default() } else { DockerIgnore::read(&root) };
+        let paths = context::glob(&root, &ignore, pattern)?;
+        Ok(paths.iter().map(|p| p.to_string_lossy().into_owned()).collect())
+    }
+
+    fn list_dir(&self, path: &str, include_ignored: bool) -> anyhow::Result<Vec<String>> {
+        let (relative, full) = self.context_path(path, include_ignored)?;
+        let root = self.0.lock().unwrap().config.context_root.clone();
+        let ignore = if include_ignored { DockerIgnore::default() } else { DockerIgnore::read(&root) };
+
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&full).with_context(|| format!("Failed to list directory: {path}"))? {
+            let name = entry?.file_name();
+            if !ignore.is_ignored(&relative.join(&name)) {
+                names.push(name.to_string_lossy().into_owned());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    fn manifest(&self, kind: manifest::Kind, path: Option<&str>, include_ignored: bool) -> anyhow::Result<Manifest> {
+        let (relative, _) = self.context_path(path.unwrap_or(kind.file_name()), include_ignored)?;
+        let root = self.0.lock().unwrap().config.context_root.clone();
+        manifest::read(kind, &root, &relative)
+    }
+
+    fn git(&self) -> Arc<Git> {
+        let mut inner = self.0.lock().unwrap();
+        let root = inner.config.context_root.clone();
+        inner.git.get_or_insert_with(|| Arc::new(Git::new(&root))).clone()
+    }
+
+    fn host(&self) -> Value {
+        let mut inner = self.0.lock().unwrap();
+        inner.host.get_or_insert_with(|| Value::from_serialize(Host::detect(docker_cmd().ok()))).clone()
+    }
+
+    /// The build id given in the config, or the git revision of the context.
+    fn build_id(&self) -> String {
+        let build_id = self.0.lock().unwrap().config.build_id.clone();
+        build_id.or_else(|| self.git().describe()).unwrap_or_default()
+    }
+
+    fn hash_file(&self, path: &str, algo: &str, include_ignored: bool) -> anyhow::Result<String> {
+        let (_, full) = self.context_path(path, include_ignored)?;
+        hash::file(hash::Algo::parse(algo)?, &full).with_context(|| format!("Failed to hash file: {path}"))
+    }
+
+    fn hash_glob(&self, pattern: &str, algo: &str, include_ignored: bool) -> anyhow::Result<String> {
+        let algo = hash::Algo::parse(algo)?;
+        let root = self.0.lock().unwrap().config.context_root.clone();
+        let ignore = if include_ignored { DockerIgnore::default() } else { DockerIgnore::read(&root) };
+        let files: Vec<_> = context::glob(&root, &ignore, pattern)?.into_iter()
+            .filter(|p| root.join(p).is_file())
+            .map(|p| (p.to_string_lossy().into_owned(), root.join(p)))
+            .collect();
+        hash::files(algo, &files)
+    }
+
+    fn random_id(&self, length: usize) -> String {
+        let seed = hash::seed(&self.build_id());
+        let mut inner = self.0.lock().unwrap();
+        let rng = inner.rng.get_or_insert_with(|| fastrand::Rng::with_seed(seed));
+        (0..length).map(|_| rng.alphanumeric().to_ascii_lowercase()).collect()
+    }
+
+    /// The stage being rendered, as in `builder (FROM rust:1.88)`.
+    fn current_stage(&self) -> Option<String> {
+        let target = self.0.lock().unwrap().target.clone()?;
+        let name = target.name.clone().unwrap_or_else(|| format!("#{}", target.index));
+        Some(format!("{name} (FROM {})", target.src))
+    }
+
     fn set_output(&self, output: Option<PathBuf>) {
         self.0.lock().unwrap().output = output;
     }
 
+    /// A `src=` path for a mount, relative to the context root like the other file functions.
+    fn mount_src(&self, src: &str) -> anyhow::Result<String> {
+        check_mount_value("src", src)?;
+        let path = self.0.lock().unwrap().config.context_root.join(src);
+        path.to_str().map(str::to_owned).ok_or_else(|| anyhow!("Invalid mount src {src:?}"))
+    }
+
+    /// Record a `--secret` flag for the build, returning the mount for `RUN`.
+    fn secret(&self, id: &str, src: Option<&str>, env: Option<&str>, options: MountOptions) -> anyhow::Result<String> {
+        check_mount_id(id)?;
+        options.check()?;
+        let source = match (src, env) {
+            (Some(src), None) => format!("src={}", self.mount_src(src)?),
+            (None, Some(env)) => {
+                check_mount_value("env", env)?;
+                format!("env={env}")
+            },
+            (None, None) => bail!("Secret {id} needs a src= file or env= variable"),
+            (Some(_), Some(_)) => bail!("Secret {id} can't have both src= and env="),
+        };
+        add_mount_flag(&mut self.0.lock().unwrap().secrets, "Secret", id, format!("id={id},{source}"))?;
+        Ok(format!("--mount=type=secret,id={id}{options}"))
+    }
+
+    /// Record an `--ssh` flag for the build, returning the mount for `RUN`.
+    fn ssh(&self, id: &str, src: Option<&str>, options: MountOptions) -> anyhow::Result<String> {
+        check_mount_id(id)?;
+        options.check()?;
+        let flag = match src {
+            Some(src) => format!("{id}={}", self.mount_src(src)?),
+            None => id.to_owned(),
+        };
+        add_mount_flag(&mut self.0.lock().unwrap().ssh, "SSH agent", id, flag)?;
+
+        if id == "default" {
+            Ok(format!("--mount=type=ssh{options}"))
+        } else {
+            Ok(format!("--mount=type=ssh,id={id}{options}"))
+        }
+    }
+
     fn add_docker_tag(&self, tag: &str) -> anyhow::Result<()> {
         self.0.lock().unwrap().docker_tags.push(tag.to_owned());
         Ok(())
@@ -99,6 +276,59 @@ impl Object for Runtime {
             }
         } else if method == "build_arg" {
             Value::from_function(move |k: &str| this.build_arg(k))
+        } else if method == "env" {
+            Value::from_function(move |n: &str, default: Option<Value>, kwargs: Kwargs| {
+                let default = match default {
+                    Some(d) => Some(d),
+                    None => kwargs.get("default")?,
+                };
+                kwargs.assert_all_used()?;
+
+                let value = this.env(n).into_value()?;
+                Ok::<_, minijinja::Error>(if value.is_none() { default.unwrap_or(value) } else { value })
+            })
+        } else if method == "read_file" {
+            Value::from_function(move |p: &str, kwargs: Kwargs| this.read_file(p, include_ignored(&kwargs)?).into_value())
+        } else if method == "file_exists" {
+            Value::from_function(move |p: &str, kwargs: Kwargs| this.file_exists(p, include_ignored(&kwargs)?).into_value())
+        } else if method == "glob" {
+            Value::from_function(move |p: &str, kwargs: Kwargs| this.glob(p, include_ignored(&kwargs)?).into_value())
+        } else if method == "list_dir" {
+            Value::from_function(move |p: &str, kwargs: Kwargs| this.list_dir(p, include_ignored(&kwargs)?).into_value())
+        } else if let Some(kind) = match method {
+            "cargo_manifest" => Some(manifest::Kind::Cargo),
+            "package_json" => Some(manifest::Kind::Npm),
+            "pyproject" => Some(manifest::Kind::Python),
+            "go_mod" => Some(manifest::Kind::Go),
+            _ => None,
+        } {
+            Value::from_function(move |p: Option<&str>, kwargs: Kwargs| this.manifest(kind, p, include_ignored(&kwargs)?).into_value())
+        } else if method == "hash_file" {
+            Value::from_function(move |p: &str, algo: Option<&str>, kwargs: Kwargs| {
+                this.hash_file(p, algo.unwrap_or("sha256"), include_ignored(&kwargs)?).into_value()
+            })
+        } else if method == "hash_glob" {
+            Value::from_function(move |p: &str, algo: Option<&str>, kwargs: Kwargs| {
+                this.hash_glob(p, algo.unwrap_or("sha256"), include_ignored(&kwargs)?).into_value()
+            })
+        } else if method == "random_id" {
+            Value::from_function(move |length: Option<usize>| this.random_id(length.unwrap_or(12)))
+        } else if method == "secret" {
+            Value::from_function(move |id: &str, kwargs: Kwargs| {
+                let src: Option<&str> = kwargs.get("src")?;
+                let env: Option<&str> = kwargs.get("env")?;
+                let options = MountOptions::from_kwargs(&kwargs)?;
+                kwargs.assert_all_used()?;
+                this.secret(id, src, env, options).into_value()
+            })
+        } else if method == "ssh" {
+            Value::from_function(move |kwargs: Kwargs| {
+                let id: Option<&str> = kwargs.get("id")?;
+                let src: Option<&str> = kwargs.get("src")?;
+                let options = MountOptions::from_kwargs(&kwargs)?;
+                kwargs.assert_all_used()?;
+                this.ssh(id.unwrap_or("default"), src, options).into_value()
+            })
         } else if method == "add_docker_tag" {
             Value::from_function(move |t: &str| this.add_docker_tag(t).into_value())
         } else if method == "version_require" {
@@ -109,10 +339,88 @@ impl Object for Runtime {
 
         method.call(state, args)
     }
+
+    fn get_value(self: &Arc<Self>, key: &Value) -> Option<Value> {
+        match key.as_str()? {
+            "git" => Some(Value::from_dyn_object(self.git())),
+            "build_id" => Some(Value::from(self.build_id())),
+            "host" => Some(self.host()),
+            "strictness" => Some(Value::from(self.0.lock().unwrap().config.strictness.to_string())),
+            _ => None,
+        }
+    }
+}
+
+/// The `target=` and `required=` arguments to `bern.secret` and `bern.ssh`, as mount options.
+#[derive(Debug, Default)]
+struct MountOptions {
+    target: Option<String>,
+    required: Option<bool>,
+}
+
+impl MountOptions {
+    fn from_kwargs(kwargs: &Kwargs) -> Result<Self, minijinja::Error> {
+        Ok(Self {
+            target: kwargs.get("target")?,
+            required: kwargs.get("required")?,
+        })
+    }
+
+    fn check(&self) -> anyhow::Result<()> {
+        if let Some(target) = &self.target {
+            check_mount_value("target", target)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for MountOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(target) = &self.target {
+            write!(f, ",target={target}")?;
+        }
+        if let Some(required) = self.required {
+            write!(f, ",required={required}")?;
+        }
+        Ok(())
+    }
+}
+
+fn check_mount_id(id: &str) -> anyhow::Result<()> {
+    if id.is_empty() || id.contains([',', '=']) || id.contains(char::is_whitespace) {
+        bail!("Invalid mount id {id:?}");
+    }
+    Ok(())
+}
+
+/// Values are joined into docker's comma separated flags, so they can't hold a comma.
+fn check_mount_value(key: &str, value: &str) -> anyhow::Result<()> {
+    if value.contains(',') {
+        bail!("Invalid mount {key} {value:?}, it can't contain a comma");
+    }
+    Ok(())
+}
+
+/// Add a flag for a mount id, which may be used more than once, but only with the same source.
+fn add_mount_flag(flags: &mut BTreeMap<String, String>, kind: &str, id: &str, flag: String) -> anyhow::Result<()> {
+    match flags.get(id) {
+        Some(existing) if *existing != flag => bail!("{kind} {id} is already used with a different source"),
+        Some(_) => {},
+        None => { flags.insert(id.to_owned(), flag); },
+    }
+    Ok(())
+}
+
+/// The `include_ignored=true` argument to the context file functions.
+fn include_ignored(kwargs: &Kwargs) -> Result<bool, minijinja::Error> {
+    let include = kwargs.get::<Option<bool>>("include_ignored")?.unwrap_or_default();
+    kwargs.assert_all_used()?;
+    Ok(include)
 }
 
 #[derive(Debug)]
 struct Target {
+    index: usize,
     src: String,
     name: Option<String>,
 }
@@ -132,6 +440,8 @@ impl Object for Target {
             Some(Value::from_safe_string(self.name.clone()?))
         } else if key == "src" {
             Some(Value::from_safe_string(self.src.clone()))
+        } else if key == "index" {
+            Some(Value::from(self.index))
         } else {
             None
         }
@@ -166,6 +476,9 @@ impl Object for CurrentTarget {
 struct RuntimeWriteLayer<W> {
     runtime: Arc<Runtime>,
     parser: DockerFileParser,
+    instructions: Vec<(usize, DockerFileInstruction)>,
+    /// Takes the template markers out of the output, for the source map
+    recorder: Recorder,
     writer: W,
 }
 
@@ -174,8 +487,11 @@ where
     W: io::Write
 {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.handle(buf, false);
-        self.writer.write(buf)
+        let mut text = Vec::with_capacity(buf.len());
+        self.recorder.write(buf, &mut text);
+        self.writer.write_all(&text)?;
+        self.handle(&text, false);
+        Ok(buf.len())
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -185,27 +501,40 @@ where
 
 impl<W: io::Write> RuntimeWriteLayer<W> {
     fn handle(&mut self, buf: &[u8], eof: bool) {
-        for item in self.parser.push(buf, eof) {
-            if let DockerFileInstruction::From { src, name } = item {
+        for (line, item) in self.parser.push_with_lines(buf, eof) {
+            if let DockerFileInstruction::From { src, name } = &item {
                 let mut lock = self.runtime.0.lock().unwrap();
                 let target = Arc::new(Target {
-                    src,
+                    index: lock.stages.len(),
+                    src: src.clone(),
                     name: name.clone()
                 });
                 if let Some(name) = name {
-                    lock.targets.insert(name, target.clone());
+                    lock.targets.insert(name.clone(), target.clone());
                 }
+                lock.stages.push(target.clone());
                 lock.target = Some(target);
             }
+            self.instructions.push((line, item));
         }
     }
 
-    fn finish(&mut self) {
-        self.handle(b"", true);
-        let _ = self.writer.flush();
+    fn finish(&mut self) -> io::Result<SourceMap> {
+        let mut text = Vec::new();
+        let source_map = self.recorder.finish(&mut text);
+        self.writer.write_all(&text)?;
+        self.handle(&text, true);
+        self.writer.flush()?;
+        Ok(source_map)
     }
 }
 
+/// Whether docker args ask for plain progress output.
+fn plain_progress(docker_args: &[String]) -> bool {
+    docker_args.iter().any(|a| a == "--progress=plain")
+        || docker_args.windows(2).any(|w| w[0] == "--progress" && w[1] == "plain")
+}
+
 fn docker_cmd() -> Result<&'static Path, anyhow::Error> {
     static DOCKER_CMD: LazyLock<Option<PathBuf>> = LazyLock::new(|| {
         let candidates = [
@@ -222,6 +551,123 @@ fn docker_cmd() -> Result<&'static Path, anyhow::Error> {
     DOCKER_CMD.as_deref().ok_or_else(|| anyhow::anyhow!("docker or podman was not found in PATH"))
 }
 
+/// A stage found in the rendered Dockerfile.
+#[derive(Debug, Clone, Serialize)]
+pub struct StageInfo {
+    pub index: usize,
+    pub name: Option<String>,
+    pub src: String,
+    /// Whether this is the last stage, which is built when no target is given
+    pub default: bool,
+}
+
+/// A docker command that was run.
+#[derive(Debug, Clone, Serialize)]
+pub struct CommandReport {
+    pub args: Vec<String>,
+    /// Exit code, if the command exited normally
+    pub status: Option<i32>,
+    pub success: bool,
+}
+
+/// A tag that was pushed.
+#[derive(Debug, Clone, Serialize)]
+pub struct PushReport {
+    pub tag: String,
+    /// Digest printed by docker, if any
+    pub digest: Option<String>,
+}
+
+/// What a build (and push) did, for `--format json`.
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    /// Send docker's stdout to stderr, keeping stdout free for the report
+    #[serde(skip)]
+    pub quiet: bool,
+    pub stages: Vec<StageInfo>,
+    pub targets: Vec<String>,
+    pub commands: Vec<CommandReport>,
+    pub tags: Vec<String>,
+    pub pushed: Vec<PushReport>,
+    pub output: Option<PathBuf>,
+    pub error: Option<String>,
+}
+
+impl Report {
+    fn run(&mut self, command: &mut Command) -> anyhow::Result<ExitStatus> {
+        if self.quiet {
+            command.stdout(io::stderr());
+        }
+
+        let status = command.status()?;
+        self.record(command, status);
+        Ok(status)
+    }
+
+    /// Run a build, pointing references to Dockerfile lines in its errors at the templates.
+    fn run_annotated(&mut self, command: &mut Command, source_map: &SourceMap) -> anyhow::Result<ExitStatus> {
+        if self.quiet {
+            command.stdout(io::stderr());
+        }
+        command.stderr(Stdio::piped());
+
+        let mut child = command.spawn()?;
+        let mut output = io::BufReader::new(child.stderr.take().context("No stderr")?);
+        let mut line = Vec::new();
+        while output.read_until(b'\n', &mut line)? > 0 {
+            let annotated = source_map.annotate_docker_output(&String::from_utf8_lossy(&line));
+            io::stderr().write_all(annotated.as_bytes())?;
+            line.clear();
+        }
+
+        let status = child.wait()?;
+        self.record(command, status);
+        Ok(status)
+    }
+
+    /// Run a build on a terminal, then repeat the lines of its error that refer to
+    /// Dockerfile lines, pointed at the templates.
+    fn run_on_terminal(&mut self, command: &mut Command, source_map: &SourceMap) -> anyhow::Result<ExitStatus> {
+        if self.quiet {
+            command.stdout(io::stderr());
+        }
+
+        let (status, output) = pty::run(command)?;
+        if !status.success() {
+            let mut seen = HashSet::new();
+            let annotated: Vec<_> = pty::plain_lines(&output).into_iter()
+                .filter_map(|line| {
+                    let annotated = source_map.annotate_docker_output(&line);
+                    (annotated != line && seen.insert(annotated.clone())).then_some(annotated)
+                })
+                .collect();
+
+            if !annotated.is_empty() {
+                eprintln!("[Bern] In the templates:");
+                for line in annotated {
+                    eprintln!("{line}");
+                }
+            }
+        }
+
+        self.record(command, status);
+        Ok(status)
+    }
+
+    fn record(&mut self, command: &Command, status: ExitStatus) {
+        let args = std::iter::once(command.get_program())
+            .chain(command.get_args())
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect();
+
+        self.commands.push(CommandReport {
+            args,
+            status: status.code(),
+            success: status.success(),
+        });
+    }
+}
+
 pub struct BernBuild {
     config: Arc<BernConfig>,
     runtime: Arc<Runtime>,
@@ -239,7 +685,12 @@ impl BernBuild {
         let runtime = Arc::new(Runtime::default());
         runtime.0.lock().unwrap().config = config.clone();
 
-        let mut jenv = template::Environment::new(&config.context_root);
+        let search_path: Vec<_> = std::iter::once(&config.context_root).chain(&config.template_path).collect();
+        let mut jenv = template::Environment::new(&search_path);
+        jenv.set_strictness(config.strictness);
+        if let Some(time) = config.timestamp.and_then(DateTime::from_timestamp) {
+            jenv.set_now(time);
+        }
         jenv.set("bern".to_owned(), minijinja::Value::from_dyn_object(runtime.clone()));
         jenv.set("target".to_owned(), minijinja::Value::from_dyn_object(Arc::new(CurrentTarget(runtime.clone()))));
 
@@ -255,31 +706,89 @@ impl BernBuild {
         rt.build_args.iter().chain(self.config.build_args.iter()).map(|(k,v)| format!("{k}={v}")).collect()
     }
 
+    /// `--secret` and `--ssh` flags for the mounts the template used.
+    fn mount_flags(&self) -> Vec<String> {
+        let rt = self.runtime.0.lock().unwrap();
+        let secrets = rt.secrets.values().flat_map(|s| ["--secret".to_owned(), s.clone()]);
+        let ssh = rt.ssh.values().flat_map(|s| ["--ssh".to_owned(), s.clone()]);
+        secrets.chain(ssh).collect()
+    }
+
     fn docker_tags(&self) -> Vec<String> {
         let rt = self.runtime.0.lock().unwrap();
         self.config.docker_tags.iter().chain(rt.docker_tags.iter()).cloned().collect()
     }
 
-    fn output(&self) -> Option<PathBuf> {
+    pub fn output(&self) -> Option<PathBuf> {
         let rt = self.runtime.0.lock().unwrap();
         self.config.output.clone().or_else(|| rt.output.clone())
     }
 
     pub fn render_to<W>(&self, writer: W) -> anyhow::Result<()>
+    where
+        W: std::io::Write
+    {
+        self.render_instructions(writer)?;
+        Ok(())
+    }
+
+    /// Render the template, returning the parsed Dockerfile instructions with their line numbers.
+    pub fn render_instructions<W>(&self, writer: W) -> anyhow::Result<Vec<(usize, DockerFileInstruction)>>
     where
         W: std::io::Write
     {
         let mut rt_writer = RuntimeWriteLayer {
             runtime: self.runtime.clone(),
             parser: DockerFileParser::new(),
+            instructions: Vec::new(),
+            recorder: Recorder::default(),
             writer,
         };
 
-        self.jenv.render_to(&self.config.file, &mut rt_writer)?;
+        // The same ids for each render of the same build
+        self.runtime.0.lock().unwrap().rng = None;
 
-        rt_writer.finish();
+        // Loaded for each render, so that watch picks up changes
+        let vars = vars::load(&self.config.vars_files)?;
+        let extra = HashMap::from([("vars".to_owned(), Value::from_serialize(vars))]);
+        if let Err(mut e) = self.jenv.render_to(&self.config.file, extra, &mut rt_writer) {
+            if let Some(render_error) = e.downcast_mut::<RenderError>() {
+                render_error.stage = self.runtime.current_stage();
+            }
+            return Err(e);
+        }
 
-        Ok(())
+        let source_map = rt_writer.finish()?;
+        self.runtime.0.lock().unwrap().source_map = Arc::new(source_map);
+
+        Ok(rt_writer.instructions)
+    }
+
+    /// Template origins of the lines of the most recent render.
+    pub fn source_map(&self) -> Arc<SourceMap> {
+        self.runtime.0.lock().unwrap().source_map.clone()
+    }
+
+    /// Names and files of the templates loaded through includes by the most recent render.
+    pub fn loaded_templates(&self) -> Vec<(String, PathBuf)> {
+        self.jenv.loaded_templates()
+    }
+
+    /// Build args read through `bern.build_arg` by the most recent render.
+    pub fn read_build_args(&self) -> Vec<String> {
+        self.runtime.0.lock().unwrap().read_build_args.iter().cloned().collect()
+    }
+
+    /// Stages found by the most recent render, in order.
+    pub fn stages(&self) -> Vec<StageInfo> {
+        let rt = self.runtime.0.lock().unwrap();
+        let count = rt.stages.len();
+        rt.stages.iter().map(|t| StageInfo {
+            index: t.index,
+            name: t.name.clone(),
+            src: t.src.clone(),
+            default: t.index + 1 == count,
+        }).collect()
     }
 
     fn build_targets(&self) -> impl Iterator<Item=BuildTarget<'_>> {
@@ -317,12 +826,24 @@ impl BernBuild {
         Ok(cmd)
     }
 
-    pub fn build(&self) -> anyhow::Result<()> {
+    pub fn build(&self, report: &mut Report) -> anyhow::Result<()> {
         let df_path: PathBuf = self.config.stage_dir.join("Dockerfile");
         let df_file = BufWriter::new(fs::File::create(&df_path).with_context(|| format!("Failed to write file: {}", df_path.display()))?);
 
         self.render_to(df_file)?;
 
+        let source_map = self.source_map();
+        let map_path = self.config.stage_dir.join("Dockerfile.map.json");
+        fs::write(&map_path, serde_json::to_string(&*source_map)?)
+            .with_context(|| format!("Failed to write file: {}", map_path.display()))?;
+        // Docker's interactive progress needs its output to be a terminal, so when it gets
+        // one the annotated lines are only repeated after a failure
+        let annotate = !io::stderr().is_terminal() || plain_progress(&self.config.docker_args);
+
+        report.stages = self.stages();
+        report.targets = self.config.targets.clone();
+        report.output = self.output();
+
         let mut docker_tags = self.docker_tags().into_iter();
         let first_docker_tag = docker_tags.next();
 
@@ -334,6 +855,7 @@ impl BernBuild {
             for build_arg in self.build_args() {
                 command.arg("--build-arg").arg(build_arg);
             }
+            command.args(self.mount_flags());
 
             if let Some(output) = self.output() {
                 let mut output_arg = OsString::from("type=local,dest=");
@@ -348,10 +870,15 @@ impl BernBuild {
 
             if target.last && let Some(docker_tag) = first_docker_tag.as_deref() {
                 command.arg("-t").arg(docker_tag);
+                report.tags.push(docker_tag.to_owned());
             }
 
             command.arg(&self.config.context_root);
-            let status = command.status()?;
+            let status = if annotate {
+                report.run_annotated(&mut command, &source_map)?
+            } else {
+                report.run_on_terminal(&mut command, &source_map)?
+            };
 
             if !status.success() {
                 bail!("Build failed with {status}")
@@ -359,17 +886,21 @@ impl BernBuild {
         }
 
         for tag in docker_tags {
-            Command::new(docker_cmd()?)
+            let status = report.run(Command::new(docker_cmd()?)
                 .arg("tag")
                 .arg(first_docker_tag.as_ref().unwrap())
-                .arg(&tag)
-                .spawn()?;
+                .arg(&tag))?;
+
+            if !status.success() {
+                bail!("Tagging {tag} failed with {status}")
+            }
+            report.tags.push(tag);
         }
 
         Ok(())
     }
 
-    pub fn push(&self) -> anyhow::Result<()> {
+    pub fn push(&self, report: &mut Report) -> anyhow::Result<()> {
         let docker_tags = self.docker_tags();
         if docker_tags.is_empty() {
             bail!("Tag not set");
@@ -378,29 +909,40 @@ impl BernBuild {
                 let mut command = Command::new(docker_cmd()?);
                 command.arg("push").arg(&tag);
 
-                let status = command.status()?;
+                // The digest is only needed for the report, so leave docker's progress alone otherwise
+                let mut digest = None;
+                let status = if report.quiet {
+                    // Pass the output through to stderr, looking for the digest on the way
+                    let mut child = command.stdout(Stdio::piped()).spawn()?;
+                    for line in io::BufReader::new(child.stdout.take().context("No stdout")?).lines() {
+                        let line = line?;
+                        if let Some((_, rest)) = line.split_once("digest: ") {
+                            digest = rest.split_whitespace().next().map(str::to_owned);
+                        }
+                        writeln!(io::stderr(), "{line}")?;
+                    }
+                    child.wait()?
+                } else {
+                    command.status()?
+                };
+                report.record(&command, status);
                 if !status.success() {
                     bail!("Tag push for {tag} failed with {status}")
                 }
+
+                report.pushed.push(PushReport { tag, digest });
             }
 
             Ok(())
         }
     }
 
-    fn read_dockerignore(&self) -> Vec<glob::Pattern> {
-        let Ok(f) = fs::File::open(self.config.context_root.join(".dockerignore")) else { return Vec::new() };
-
-        io::BufReader::new(f).lines()
-            .map_while(|a| a.ok())
-            .map(|a| a.trim().to_string())
-            .filter(|a| !a.starts_with("#") && !a.is_empty())
-            .filter_map(|a| glob::Pattern::new(&a).ok())
-            .collect()
+    fn read_dockerignore(&self) -> DockerIgnore {
+        DockerIgnore::read(&self.config.context_root)
     }
 
     pub fn export_context(&self, w: impl io::Write) -> anyhow::Result<()> {
-        let globs = self.read_dockerignore();
+        let ignore = self.read_dockerignore();
 
         let mut tar = tar::Builder::new(w);
 
@@ -416,13 +958,10 @@ impl BernBuild {
         tar.append(&header, &dockerfile as &[u8])
             .with_context(|| anyhow::anyhow!("Failed to write dockerfile to tar"))?;
 
-        for entry in walkdir::WalkDir::new(&self.config.context_root) {
+        for entry in context::walk(&self.config.context_root, &ignore) {
             let entry = entry?;
             let path = entry.path();
-            if globs.iter().any(|g| g.matches_path(path)) {
-                continue;
-            }
-            
+
             tar.append_path(path)
                 .with_context(|| anyhow::anyhow!("Failed to write {} to tar", path.display()))?;
         }
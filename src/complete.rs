@@ -0,0 +1,83 @@
+use std::{collections::BTreeSet, ffi::{OsStr, OsString}, io};
+
+use clap::CommandFactory as _;
+use clap_complete::CompletionCandidate;
+
+use crate::{build::BernBuild, dockerfile::DockerFileInstruction, Cli};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum CompletionShell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+/// Write the script that hooks bern's completions into the shell.
+pub fn write_registration(shell: CompletionShell, w: &mut dyn io::Write) -> io::Result<()> {
+    let completer: &dyn clap_complete::env::EnvCompleter = match shell {
+        CompletionShell::Bash => &clap_complete::env::Bash,
+        CompletionShell::Zsh => &clap_complete::env::Zsh,
+        CompletionShell::Fish => &clap_complete::env::Fish,
+    };
+    completer.write_registration("COMPLETE", "bern", "bern", "bern", w)
+}
+
+/// Answer a completion request from the shell, if this is one.
+pub fn complete() {
+    clap_complete::CompleteEnv::with_factory(Cli::command).complete();
+}
+
+/// Write a man page for bern.
+pub fn write_man(w: &mut dyn io::Write) -> io::Result<()> {
+    clap_mangen::Man::new(Cli::command()).render(w)
+}
+
+/// Render the template with the arguments on the command line being completed.
+///
+/// The word being completed (and the flag it belongs to) may not parse yet, so drop
+/// words from the end until what remains does.
+fn render() -> Option<(BernBuild, Vec<(usize, DockerFileInstruction)>)> {
+    let mut words: Vec<OsString> = std::env::args_os().skip_while(|a| a != "--").skip(1).collect();
+    let cli = loop {
+        words.pop()?;
+        if let Ok(cli) = Cli::command().try_get_matches_from(&words).and_then(|m| Cli::from_matches(&m)) {
+            break cli;
+        }
+    };
+
+    let settings = crate::load_settings(&cli, cli.profile.as_deref(), !cli.profile_from_env).ok()?;
+    let stage_dir = tempfile::tempdir().ok()?;
+    let build = BernBuild::new(crate::bern_config(settings, stage_dir.path()));
+    let instructions = build.render_instructions(io::sink()).ok()?;
+    Some((build, instructions))
+}
+
+fn matching(current: &OsStr, values: impl IntoIterator<Item = String>) -> Vec<CompletionCandidate> {
+    let current = current.to_string_lossy();
+    values.into_iter()
+        .filter(|v| v.starts_with(current.as_ref()))
+        .map(CompletionCandidate::new)
+        .collect()
+}
+
+/// Stage names in the resulting Dockerfile.
+pub fn targets(current: &OsStr) -> Vec<CompletionCandidate> {
+    let Some((build, _)) = render() else { return Vec::new() };
+    matching(current, build.stages().into_iter().filter_map(|s| s.name))
+}
+
+/// Build args read by the template or declared with `ARG`.
+pub fn build_args(current: &OsStr) -> Vec<CompletionCandidate> {
+    let Some((build, instructions)) = render() else { return Vec::new() };
+
+    let mut names: BTreeSet<_> = build.read_build_args().into_iter().collect();
+    for (_, instr) in &instructions {
+        if let DockerFileInstruction::Other(name, args) = instr
+            && name.eq_ignore_ascii_case("ARG")
+        {
+            names.extend(args.split_whitespace().map(|a| a.split('=').next().unwrap_or(a).to_owned()));
+        }
+    }
+
+    matching(current, names.into_iter().map(|n| format!("{n}=")))
+}
@@ -0,0 +1,342 @@
+use std::{collections::HashMap, fs, path::{Path, PathBuf}};
+
+use anyhow::{anyhow, bail, Context as _};
+use serde::Deserialize;
+
+use crate::{template::Strictness, time};
+
+pub const CONFIG_FILE_NAME: &str = "bern.toml";
+
+/// Directory for templates shared by all projects of the user, searched after any
+/// template paths.
+pub fn user_template_dir() -> Option<PathBuf> {
+    let config = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| Path::new(&h).join(".config")))?;
+    Some(config.join("bern").join("templates"))
+}
+
+/// Build settings, as found in a config file or given on the command line.
+///
+/// Unset values are `None` so that layers can be merged on top of each other.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Settings {
+    pub file: Option<PathBuf>,
+    pub context: Option<PathBuf>,
+    pub template_path: Option<Vec<PathBuf>>,
+    pub vars_files: Option<Vec<PathBuf>>,
+    pub allow_env: Option<Vec<String>>,
+    pub build_id: Option<String>,
+    /// Seconds since the epoch
+    #[serde(deserialize_with = "timestamp")]
+    pub timestamp: Option<i64>,
+    pub strictness: Option<Strictness>,
+    pub docker_args: Option<Vec<String>>,
+    pub build_args: Option<HashMap<String, String>>,
+    pub tags: Option<Vec<String>>,
+    pub targets: Option<Vec<String>>,
+    pub output: Option<PathBuf>,
+    pub push: Option<bool>,
+    pub lint: Option<LintSettings>,
+}
+
+/// A timestamp as seconds since the epoch, or an RFC 3339 string.
+fn timestamp<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<i64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Timestamp {
+        Seconds(i64),
+        Text(String),
+    }
+
+    let text = match Timestamp::deserialize(deserializer)? {
+        Timestamp::Seconds(seconds) => seconds.to_string(),
+        Timestamp::Text(text) => text,
+    };
+    time::parse_timestamp(&text).map(Some).map_err(serde::de::Error::custom)
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct LintSettings {
+    /// Rules to enable that are off by default
+    pub enable: Vec<String>,
+    /// Rules to disable
+    pub disable: Vec<String>,
+}
+
+impl Settings {
+    /// Apply values from `other` on top of these settings.
+    ///
+    /// Build args are merged by name, template paths from `other` are searched first,
+    /// vars files from `other` are loaded last, and allowed environment variables are
+    /// added to.  Everything else is replaced.
+    pub fn merge(&mut self, other: Settings) {
+        fn replace<T>(dest: &mut Option<T>, src: Option<T>) {
+            if src.is_some() {
+                *dest = src;
+            }
+        }
+
+        replace(&mut self.file, other.file);
+        replace(&mut self.context, other.context);
+        replace(&mut self.build_id, other.build_id);
+        replace(&mut self.timestamp, other.timestamp);
+        replace(&mut self.strictness, other.strictness);
+        replace(&mut self.docker_args, other.docker_args);
+        replace(&mut self.tags, other.tags);
+        replace(&mut self.targets, other.targets);
+        replace(&mut self.output, other.output);
+        replace(&mut self.push, other.push);
+        replace(&mut self.lint, other.lint);
+
+        if let Some(build_args) = other.build_args {
+            self.build_args.get_or_insert_default().extend(build_args);
+        }
+
+        if let Some(mut template_path) = other.template_path {
+            template_path.extend(self.template_path.take().unwrap_or_default());
+            self.template_path = Some(template_path);
+        }
+
+        if let Some(vars_files) = other.vars_files {
+            self.vars_files.get_or_insert_default().extend(vars_files);
+        }
+
+        if let Some(allow_env) = other.allow_env {
+            self.allow_env.get_or_insert_default().extend(allow_env);
+        }
+    }
+
+    /// Check values whose syntax can't be checked while parsing.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for pattern in self.allow_env.iter().flatten() {
+            glob::Pattern::new(pattern).with_context(|| format!("Invalid allow-env pattern: {pattern}"))?;
+        }
+        Ok(())
+    }
+
+    fn resolve_paths(&mut self, base: &Path) {
+        let lists = self.template_path.iter_mut().chain(self.vars_files.iter_mut()).flatten();
+        for path in [&mut self.file, &mut self.context, &mut self.output].into_iter().flatten().chain(lists) {
+            *path = base.join(&*path);
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Profile {
+    pub inherits: Option<String>,
+    pub settings: Settings,
+}
+
+impl Profile {
+    fn from_table(mut table: toml::Table) -> anyhow::Result<Self> {
+        let inherits = match table.remove("inherits") {
+            Some(toml::Value::String(s)) => Some(s),
+            Some(_) => bail!("inherits must be a profile name"),
+            None => None,
+        };
+
+        Ok(Self {
+            inherits,
+            settings: table.try_into()?,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ConfigFile {
+    pub settings: Settings,
+    pub profiles: HashMap<String, Profile>,
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file: {}", path.display()))?;
+        let mut config = Self::parse(&contents)
+            .with_context(|| format!("Failed to parse config file: {}", path.display()))?;
+
+        // Paths in the config file are relative to the file itself
+        let base = path.parent().unwrap_or(Path::new(""));
+        config.settings.resolve_paths(base);
+        for profile in config.profiles.values_mut() {
+            profile.settings.resolve_paths(base);
+        }
+
+        Ok(config)
+    }
+
+    fn parse(contents: &str) -> anyhow::Result<Self> {
+        let mut table: toml::Table = toml::from_str(contents)?;
+
+        let profiles = match table.remove("profile") {
+            Some(toml::Value::Table(profiles)) => profiles.into_iter()
+                .map(|(name, value)| {
+                    let toml::Value::Table(value) = value else {
+                        bail!("Profile {name} must be a table")
+                    };
+                    let profile = Profile::from_table(value)
+                        .with_context(|| format!("Invalid profile {name}"))?;
+                    Ok((name, profile))
+                })
+                .collect::<anyhow::Result<_>>()?,
+            Some(_) => bail!("profile must be a table"),
+            None => HashMap::new(),
+        };
+
+        Ok(Self {
+            settings: table.try_into()?,
+            profiles,
+        })
+    }
+
+    /// Settings with the given profile, and the profiles it inherits from, applied.
+    pub fn settings(&self, profile: Option<&str>) -> anyhow::Result<Settings> {
+        let mut chain = Vec::new();
+        let mut next = profile;
+        while let Some(name) = next {
+            if chain.iter().any(|(n, _)| *n == name) {
+                bail!("Profile {name} inherits from itself");
+            }
+
+            let profile = self.profiles.get(name)
+                .ok_or_else(|| anyhow!("Profile {name} is not defined"))?;
+            chain.push((name, profile));
+            next = profile.inherits.as_deref();
+        }
+
+        let mut settings = self.settings.clone();
+        for (_, profile) in chain.into_iter().rev() {
+            settings.merge(profile.settings.clone());
+        }
+
+        Ok(settings)
+    }
+
+    /// Load the explicitly requested config file, or look for one next to the template.
+    pub fn discover(explicit: Option<&Path>, template: &Path) -> anyhow::Result<Option<Self>> {
+        if let Some(path) = explicit {
+            return Self::load(path).map(Some);
+        }
+
+        let path = template.parent().unwrap_or(Path::new("")).join(CONFIG_FILE_NAME);
+        if path.is_file() {
+            Self::load(&path).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge() {
+        let mut base: Settings = toml::from_str(r#"
+            tags = ["a"]
+            targets = ["t1"]
+            build-args = { x = "1", y = "2" }
+            template-path = ["shared"]
+            vars-files = ["base.toml"]
+        "#).unwrap();
+
+        base.merge(Settings {
+            tags: Some(vec!["b".into()]),
+            template_path: Some(vec!["local".into()]),
+            vars_files: Some(vec!["local.toml".into()]),
+            build_args: Some(HashMap::from([("y".into(), "3".into())])),
+            ..Default::default()
+        });
+
+        assert_eq!(base.tags, Some(vec!["b".into()]));
+        assert_eq!(base.targets, Some(vec!["t1".into()]));
+        assert_eq!(base.template_path, Some(vec!["local".into(), "shared".into()]));
+        assert_eq!(base.vars_files, Some(vec!["base.toml".into(), "local.toml".into()]));
+        assert_eq!(base.build_args.unwrap(), HashMap::from([
+            ("x".into(), "1".into()),
+            ("y".into(), "3".into()),
+        ]));
+    }
+
+    #[test]
+    fn test_resolve_paths() {
+        let mut settings: Settings = toml::from_str(r#"
+            file = "Dockerfile.j2"
+            output = "out"
+        "#).unwrap();
+        settings.resolve_paths(Path::new("project"));
+
+        assert_eq!(settings.file, Some(PathBuf::from("project/Dockerfile.j2")));
+        assert_eq!(settings.output, Some(PathBuf::from("project/out")));
+        assert_eq!(settings.context, None);
+    }
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(toml::from_str::<Settings>("timestamp = 1700000000").unwrap().timestamp, Some(1_700_000_000));
+        assert_eq!(toml::from_str::<Settings>(r#"timestamp = "2023-11-14T22:13:20Z""#).unwrap().timestamp, Some(1_700_000_000));
+        assert_eq!(toml::from_str::<Settings>("").unwrap().timestamp, None);
+        assert!(toml::from_str::<Settings>(r#"timestamp = "soon""#).is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(toml::from_str::<Settings>(r#"allow-env = ["CI_*", "HTTP?_PROXY"]"#).unwrap().validate().is_ok());
+        let err = toml::from_str::<Settings>(r#"allow-env = ["CI_[A-"]"#).unwrap().validate().unwrap_err();
+        assert_eq!(err.to_string(), "Invalid allow-env pattern: CI_[A-");
+    }
+
+    #[test]
+    fn test_unknown_field() {
+        assert!(toml::from_str::<Settings>("meow = 1").is_err());
+        assert!(ConfigFile::parse("meow = 1").is_err());
+        assert!(ConfigFile::parse("[profile.dev]\nmeow = 1").is_err());
+    }
+
+    #[test]
+    fn test_profiles() {
+        let config = ConfigFile::parse(r#"
+            tags = ["base"]
+            build-args = { x = "base" }
+
+            [profile.ci]
+            build-args = { x = "ci", y = "ci" }
+
+            [profile.release]
+            inherits = "ci"
+            tags = ["release"]
+            build-args = { y = "release" }
+        "#).unwrap();
+
+        let base = config.settings(None).unwrap();
+        assert_eq!(base.tags, Some(vec!["base".into()]));
+
+        let release = config.settings(Some("release")).unwrap();
+        assert_eq!(release.tags, Some(vec!["release".into()]));
+        assert_eq!(release.build_args.unwrap(), HashMap::from([
+            ("x".into(), "ci".into()),
+            ("y".into(), "release".into()),
+        ]));
+
+        assert!(config.settings(Some("missing")).is_err());
+    }
+
+    #[test]
+    fn test_profile_cycle() {
+        let config = ConfigFile::parse(r#"
+            [profile.a]
+            inherits = "b"
+
+            [profile.b]
+            inherits = "a"
+        "#).unwrap();
+
+        assert!(config.settings(Some("a")).is_err());
+    }
+}
@@ -0,0 +1,190 @@
+use std::{fs, io::{self, BufRead as _}, path::{Component, Path, PathBuf}};
+
+use anyhow::{bail, Context as _};
+
+const MATCH_OPTIONS: glob::MatchOptions = glob::MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Patterns from a context's .dockerignore file.
+#[derive(Debug, Default, Clone)]
+pub struct DockerIgnore {
+    /// Patterns in file order, with whether they exclude (or re-include with `!`)
+    patterns: Vec<(bool, glob::Pattern)>,
+}
+
+impl DockerIgnore {
+    pub fn read(root: &Path) -> Self {
+        let Ok(f) = fs::File::open(root.join(".dockerignore")) else { return Self::default() };
+
+        let patterns = io::BufReader::new(f).lines()
+            .map_while(|a| a.ok())
+            .map(|a| a.trim().to_string())
+            .filter(|a| !a.starts_with("#") && !a.is_empty())
+            .filter_map(|a| {
+                let (exclude, pattern) = match a.strip_prefix('!') {
+                    Some(p) => (false, p.trim()),
+                    None => (true, a.as_str()),
+                };
+                let pattern = pattern.trim_start_matches("./").trim_start_matches('/');
+                glob::Pattern::new(pattern).ok().map(|p| (exclude, p))
+            })
+            .collect();
+
+        Self { patterns }
+    }
+
+    /// Check a path relative to the context root.
+    ///
+    /// A path is also ignored if one of its parent directories is.
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        let mut ignored = false;
+        for (exclude, pattern) in &self.patterns {
+            let matches = path.ancestors()
+                .filter(|p| !p.as_os_str().is_empty())
+                .any(|p| pattern.matches_path_with(p, MATCH_OPTIONS));
+            if matches {
+                ignored = *exclude;
+            }
+        }
+        ignored
+    }
+
+    fn has_exceptions(&self) -> bool {
+        self.patterns.iter().any(|(exclude, _)| !exclude)
+    }
+}
+
+/// Walk the files and directories of the context that are not ignored.
+pub fn walk<'a>(root: &'a Path, ignore: &'a DockerIgnore) -> impl Iterator<Item = walkdir::Result<walkdir::DirEntry>> + 'a {
+    // Only skip whole directories if nothing inside them could be re-included
+    let prune = !ignore.has_exceptions();
+    let relative = move |e: &walkdir::DirEntry| e.path().strip_prefix(root).map(Path::to_owned).unwrap_or_default();
+
+    walkdir::WalkDir::new(root)
+        .into_iter()
+        .filter_entry(move |e| !(prune && e.file_type().is_dir() && ignore.is_ignored(&relative(e))))
+        .filter(move |e| e.as_ref().map(|e| !ignore.is_ignored(&relative(e))).unwrap_or(true))
+}
+
+/// Resolve a path relative to the context root, making sure it stays inside.
+///
+/// Returns the normalized relative path along with the full path.
+pub fn resolve(root: &Path, path: &str) -> anyhow::Result<(PathBuf, PathBuf)> {
+    let mut relative = PathBuf::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(c) => relative.push(c),
+            Component::CurDir => {},
+            Component::ParentDir if relative.pop() => {},
+            _ => bail!("Path {path} is outside of the context"),
+        }
+    }
+
+    let full = root.join(&relative);
+
+    // Symlinks could still point elsewhere
+    if let Ok(canonical) = full.canonicalize() {
+        let root = root.canonicalize().with_context(|| format!("Failed to read context {}", root.display()))?;
+        if !canonical.starts_with(&root) {
+            bail!("Path {path} is outside of the context");
+        }
+    }
+
+    Ok((relative, full))
+}
+
+/// Relative paths in the context matching a glob pattern, sorted.
+pub fn glob(root: &Path, ignore: &DockerIgnore, pattern: &str) -> anyhow::Result<Vec<PathBuf>> {
+    let pattern = glob::Pattern::new(pattern.trim_start_matches("./"))
+        .with_context(|| format!("Invalid glob pattern {pattern}"))?;
+
+    let mut paths = Vec::new();
+    for entry in walk(root, ignore) {
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(root).unwrap_or(entry.path());
+        if !relative.as_os_str().is_empty() && pattern.matches_path_with(relative, MATCH_OPTIONS) {
+            paths.push(relative.to_owned());
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn ignore(contents: &str) -> (tempfile::TempDir, DockerIgnore) {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join(".dockerignore"), contents).unwrap();
+        let ignore = DockerIgnore::read(dir.path());
+        (dir, ignore)
+    }
+
+    #[test]
+    fn test_is_ignored() {
+        let (_dir, ignore) = ignore("# comment\ntarget\n/out/*.txt\n*.md\n!README.md\n");
+
+        assert!(ignore.is_ignored(Path::new("target")));
+        assert!(ignore.is_ignored(Path::new("target/debug/bern")));
+        assert!(ignore.is_ignored(Path::new("out/a.txt")));
+        assert!(!ignore.is_ignored(Path::new("out/a.bin")));
+        assert!(ignore.is_ignored(Path::new("CHANGES.md")));
+        assert!(!ignore.is_ignored(Path::new("README.md")));
+        assert!(!ignore.is_ignored(Path::new("src/target.rs")));
+        assert!(!ignore.is_ignored(Path::new("docs/a.md")));
+    }
+
+    #[test]
+    fn test_resolve() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+
+        assert_eq!(resolve(root, "./a/../b/c").unwrap(), (PathBuf::from("b/c"), root.join("b/c")));
+        assert!(resolve(root, "../a").is_err());
+        assert!(resolve(root, "a/../../b").is_err());
+        assert!(resolve(root, "/etc/passwd").is_err());
+    }
+
+    #[test]
+    fn test_glob() {
+        let (dir, ignore) = ignore("target\n");
+        fs::create_dir_all(dir.path().join("crates/a")).unwrap();
+        fs::create_dir_all(dir.path().join("target/crates/c")).unwrap();
+        fs::write(dir.path().join("crates/a/Cargo.toml"), "").unwrap();
+        fs::write(dir.path().join("Cargo.toml"), "").unwrap();
+        fs::write(dir.path().join("target/crates/c/Cargo.toml"), "").unwrap();
+
+        assert_eq!(glob(dir.path(), &ignore, "crates/*/Cargo.toml").unwrap(), vec![PathBuf::from("crates/a/Cargo.toml")]);
+        assert_eq!(glob(dir.path(), &ignore, "**/Cargo.toml").unwrap(), vec![
+            PathBuf::from("Cargo.toml"),
+            PathBuf::from("crates/a/Cargo.toml"),
+        ]);
+    }
+
+    #[test]
+    fn test_walk() {
+        let (dir, ignore) = ignore("target\n");
+        fs::create_dir_all(dir.path().join("target/debug")).unwrap();
+        fs::create_dir_all(dir.path().join("src")).unwrap();
+        fs::write(dir.path().join("target/debug/bern"), "").unwrap();
+        fs::write(dir.path().join("src/main.rs"), "").unwrap();
+
+        let mut files: Vec<_> = walk(dir.path(), &ignore)
+            .map(|e| e.unwrap().path().strip_prefix(dir.path()).unwrap().to_owned())
+            .collect();
+        files.sort();
+
+        assert_eq!(files, vec![
+            PathBuf::from(""),
+            PathBuf::from(".dockerignore"),
+            PathBuf::from("src"),
+            PathBuf::from("src/main.rs"),
+        ]);
+    }
+}
@@ -0,0 +1,282 @@
+use std::{error::Error as _, fmt, ops::Range};
+
+use crate::sourcemap;
+
+/// Lines of source shown on either side of the failing line.
+const CONTEXT_LINES: usize = 2;
+
+/// A failed render, with where it failed and how it got there.
+#[derive(Debug)]
+pub struct RenderError {
+    message: String,
+    location: Option<Location>,
+    snippet: Option<Snippet>,
+    /// Macros and includes that led to the failure, innermost first
+    chain: Vec<String>,
+    /// The stage whose instructions were being rendered
+    pub stage: Option<String>,
+}
+
+#[derive(Debug)]
+struct Location {
+    name: String,
+    line: usize,
+    column: Option<usize>,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.name, self.line)?;
+        if let Some(column) = self.column {
+            write!(f, ":{column}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct Snippet {
+    /// Line number of the first line
+    first: usize,
+    lines: Vec<String>,
+    /// Line number, start column and width of the caret
+    caret: Option<(usize, usize, usize)>,
+}
+
+impl RenderError {
+    /// Describe a minijinja error from rendering `main`, whose source is `main_source`.
+    ///
+    /// `source_of` looks up the source of other templates, to find where macros were called.
+    pub fn new(err: &minijinja::Error, main: &str, main_source: &str, source_of: impl Fn(&str) -> Option<String>) -> Self {
+        // Errors in includes are wrapped in an error at the include statement
+        let mut frames = vec![err];
+        let mut innermost = err;
+        while let Some(inner) = innermost.source().and_then(|s| s.downcast_ref::<minijinja::Error>()) {
+            frames.push(inner);
+            innermost = inner;
+        }
+
+        let mut message = match innermost.detail() {
+            Some(detail) => format!("{}: {detail}", innermost.kind()),
+            None => innermost.kind().to_string(),
+        };
+        if let Some(cause) = innermost.source() {
+            message = format!("{message}: {cause}");
+        }
+
+        let mut chain = Vec::new();
+        let mut location = None;
+        let mut snippet = None;
+        if let (Some(name), Some(line)) = (innermost.name(), innermost.line()) {
+            // Rendered sources have markers for the source map, which moved things along
+            let range = innermost.range().map(|r| match innermost.template_source() {
+                Some(marked) => sourcemap::unmarked_offset(marked, r.start)..sourcemap::unmarked_offset(marked, r.end),
+                None => r,
+            });
+            let source = innermost.template_source().map(|s| sourcemap::unmark(s).into_owned()).or_else(|| {
+                if name == main { Some(main_source.to_owned()) } else { source_of(name) }
+            });
+
+            let column = source.as_deref().zip(range.clone()).map(|(s, r)| column(s, r.start));
+            location = Some(Location { name: name.to_owned(), line, column });
+
+            if let Some(source) = &source {
+                snippet = Some(Snippet::new(source, line, range.clone()));
+
+                let offset = range.map(|r| r.start).or_else(|| line_offset(source, line)).unwrap_or(0);
+                let macros = enclosing_macros(&source[..offset]);
+                chain.extend(macros.iter().map(|m| format!("in macro {m}")));
+
+                // The outermost macro was called by the template the innermost frame rendered
+                let caller = match frames.len() {
+                    1 => main,
+                    n => included_name(frames[n - 2]).unwrap_or(name),
+                };
+                let caller_source = if caller == main { Some(main_source.to_owned()) } else { source_of(caller) };
+                if let Some(outermost) = macros.last() && let Some(call) = caller_source.and_then(|s| call_site(&s, outermost)) {
+                    chain.push(format!("called from {caller}:{call}"));
+                }
+            }
+        }
+
+        for frame in frames[..frames.len() - 1].iter().rev() {
+            if let (Some(name), Some(line)) = (frame.name(), frame.line()) {
+                chain.push(format!("included from {name}:{line}"));
+            }
+        }
+
+        Self {
+            message,
+            location,
+            snippet,
+            chain,
+            stage: None,
+        }
+    }
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)?;
+
+        let width = self.snippet.as_ref()
+            .map(|s| (s.first + s.lines.len()).to_string().len())
+            .unwrap_or(1);
+        let pad = " ".repeat(width);
+
+        if let Some(location) = &self.location {
+            write!(f, "\n{pad}--> {location}")?;
+        }
+        if let Some(snippet) = &self.snippet {
+            write!(f, "\n{pad} |")?;
+            for (idx, line) in snippet.lines.iter().enumerate() {
+                let number = snippet.first + idx;
+                write!(f, "\n{number:>width$} | {line}")?;
+                if let Some((caret_line, start, len)) = snippet.caret && caret_line == number {
+                    write!(f, "\n{pad} | {}{}", " ".repeat(start - 1), "^".repeat(len))?;
+                }
+            }
+            write!(f, "\n{pad} |")?;
+        }
+        for link in &self.chain {
+            write!(f, "\n{pad} = {link}")?;
+        }
+        if let Some(stage) = &self.stage {
+            write!(f, "\n{pad} = while rendering stage {stage}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RenderError {}
+
+impl Snippet {
+    fn new(source: &str, line: usize, range: Option<Range<usize>>) -> Self {
+        let lines: Vec<_> = source.lines().collect();
+        let first = line.saturating_sub(CONTEXT_LINES).max(1);
+        let last = (line + CONTEXT_LINES).min(lines.len());
+
+        let caret = range.map(|r| {
+            let start = column(source, r.start);
+            let line_end = source[r.start..].find('\n').map_or(source.len(), |i| r.start + i);
+            let len = source[r.start..r.end.min(line_end)].chars().count().max(1);
+            (line, start, len)
+        });
+
+        Self {
+            first,
+            lines: lines.get(first - 1..last).unwrap_or_default().iter().map(|l| l.to_string()).collect(),
+            caret,
+        }
+    }
+}
+
+/// 1-based column of a byte offset, in characters.
+fn column(source: &str, offset: usize) -> usize {
+    let start = source[..offset].rfind('\n').map_or(0, |i| i + 1);
+    source[start..offset].chars().count() + 1
+}
+
+/// Byte offset of the start of a 1-based line.
+fn line_offset(source: &str, line: usize) -> Option<usize> {
+    if line == 1 {
+        return Some(0);
+    }
+    source.match_indices('\n').nth(line - 2).map(|(i, _)| i + 1)
+}
+
+/// The template a `BadInclude` error says failed, as in `error in "name"`.
+fn included_name(err: &minijinja::Error) -> Option<&str> {
+    if err.kind() != minijinja::ErrorKind::BadInclude {
+        return None;
+    }
+    err.detail()?.strip_prefix("error in \"")?.strip_suffix('"')
+}
+
+/// Names of the `{% macro %}` blocks still open at the end of `source`, innermost first.
+fn enclosing_macros(source: &str) -> Vec<String> {
+    let mut open = Vec::new();
+    for block in source.split("{%").skip(1) {
+        let block = block.trim_start_matches(['-', '+']).trim_start();
+        if let Some(rest) = block.strip_prefix("macro") {
+            let name: String = rest.trim_start().chars().take_while(|c| c.is_alphanumeric() || *c == '_').collect();
+            open.push(name);
+        } else if block.starts_with("endmacro") {
+            open.pop();
+        }
+    }
+    open.reverse();
+    open
+}
+
+/// Line of the only call of a macro in `source`, if it is called exactly once.
+fn call_site(source: &str, name: &str) -> Option<usize> {
+    let calls: Vec<_> = source.match_indices(&format!("{name}("))
+        .filter(|(i, _)| {
+            let before = &source[..*i];
+            let preceded_by_ident = before.chars().next_back().is_some_and(|c| c.is_alphanumeric() || c == '_');
+            !preceded_by_ident && !before.trim_end().ends_with("macro")
+        })
+        .map(|(i, _)| source[..i].matches('\n').count() + 1)
+        .collect();
+
+    match calls[..] {
+        [line] => Some(line),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Render the first of `templates`, marked for the source map like bern does.
+    fn render(templates: &[(&'static str, &'static str)]) -> RenderError {
+        let mut env = minijinja::Environment::new();
+        env.set_debug(true);
+        for (name, source) in templates {
+            env.add_template_owned(*name, sourcemap::mark(name, source)).unwrap();
+        }
+        let (main, main_source) = templates[0];
+        let err = env.get_template(main).unwrap().render(()).unwrap_err();
+        RenderError::new(&err, main, main_source, |name| env.get_template(name).ok().map(|t| sourcemap::unmark(t.source()).into_owned()))
+    }
+
+    #[test]
+    fn test_snippet() {
+        let err = render(&[("main.j2", "FROM a\nRUN b\nLABEL x={{ missing.attr }}\nRUN c\nRUN d\nRUN e\n")]);
+        assert_eq!(err.to_string(), "\
+undefined value
+ --> main.j2:3:12
+  |
+1 | FROM a
+2 | RUN b
+3 | LABEL x={{ missing.attr }}
+  |            ^^^^^^^^^^^^
+4 | RUN c
+5 | RUN d
+  |");
+    }
+
+    #[test]
+    fn test_chain() {
+        let err = render(&[
+            ("main.j2", "FROM a\n{% include \"stage.j2\" %}\n"),
+            ("stage.j2", "{% import \"macros.j2\" as m %}\nRUN {{ m.install(\"x\") }}\n"),
+            ("macros.j2", "{% macro install(pkg) %}\napt-get install {{ pkg.version.major }}\n{% endmacro %}\n"),
+        ]);
+        let out = err.to_string();
+        assert!(out.contains("--> macros.j2:2:"), "{out}");
+        assert!(out.ends_with("\
+  = in macro install
+  = called from stage.j2:2
+  = included from main.j2:2"), "{out}");
+    }
+
+    #[test]
+    fn test_enclosing_macros() {
+        assert_eq!(enclosing_macros("{% macro a() %}{% endmacro %}{%- macro b(x) %}{% macro c %}"), vec!["c", "b"]);
+        assert!(enclosing_macros("{% macro a() %}x{% endmacro %}").is_empty());
+        assert_eq!(call_site("{% macro a() %}{% endmacro %}\n{{ a() }}\n{{ data() }}", "a"), Some(2));
+    }
+}
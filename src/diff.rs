@@ -0,0 +1,138 @@
+use std::{collections::HashMap, fmt, path::Path, process::Command};
+
+use anyhow::{bail, Context as _};
+use serde::Serialize;
+
+use crate::{build::BernBuild, dockerfile::DockerFileInstruction};
+
+/// A rendered Dockerfile, split up by stage.
+#[derive(Debug)]
+pub struct Rendered {
+    text: String,
+    /// Stage name (or index, for unnamed stages) and the text of the stage
+    stages: Vec<(String, String)>,
+}
+
+impl Rendered {
+    pub fn render(build: &BernBuild) -> anyhow::Result<Self> {
+        let mut buffer = Vec::new();
+        let instructions = build.render_instructions(&mut buffer)?;
+        Ok(Self::new(String::from_utf8_lossy(&buffer).into_owned(), &instructions))
+    }
+
+    fn new(text: String, instructions: &[(usize, DockerFileInstruction)]) -> Self {
+        let starts: Vec<_> = instructions.iter()
+            .filter_map(|(line, instr)| match instr {
+                DockerFileInstruction::From { name, .. } => Some((*line, name.clone())),
+                _ => None,
+            })
+            .collect();
+
+        let lines: Vec<_> = text.split_inclusive('\n').collect();
+        let stages = starts.iter().enumerate().map(|(idx, (line, name))| {
+            let end = starts.get(idx + 1).map(|s| s.0 - 1).unwrap_or(lines.len());
+            let key = name.clone().unwrap_or_else(|| format!("#{idx}"));
+            // Trailing blank lines are not a change to the stage
+            (key, lines[(line - 1).min(end)..end].concat().trim_end().to_owned())
+        }).collect();
+
+        Self { text, stages }
+    }
+}
+
+/// Read a file as of a git revision.
+pub fn git_show(rev: &str, path: &Path) -> anyhow::Result<String> {
+    let dir = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let name = path.file_name().context("Template path has no file name")?;
+
+    let output = Command::new("git")
+        .current_dir(dir)
+        .arg("show")
+        .arg(format!("{rev}:./{}", name.to_string_lossy()))
+        .output()
+        .context("Failed to run git")?;
+
+    if !output.status.success() {
+        bail!("git show failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// How a stage changed between two renders.
+#[derive(Debug, Serialize)]
+pub struct StageChange {
+    pub name: String,
+    pub status: &'static str,
+}
+
+/// A unified diff between two renders, along with which stages changed.
+#[derive(Debug, Serialize)]
+pub struct Diff {
+    pub unified: String,
+    pub stages: Vec<StageChange>,
+}
+
+impl fmt::Display for Diff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.unified)?;
+        if !self.unified.is_empty() && !self.unified.ends_with('\n') {
+            writeln!(f)?;
+        }
+
+        writeln!(f, "Stages:")?;
+        for change in &self.stages {
+            writeln!(f, "  {:<10} {}", change.status, change.name)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn diff(old: &Rendered, new: &Rendered, old_label: &str, new_label: &str) -> Diff {
+    let unified = similar::TextDiff::from_lines(&old.text, &new.text)
+        .unified_diff()
+        .header(old_label, new_label)
+        .to_string();
+
+    let old_stages: HashMap<_, _> = old.stages.iter().map(|(k, v)| (k, v)).collect();
+    let new_stages: HashMap<_, _> = new.stages.iter().map(|(k, v)| (k, v)).collect();
+
+    let mut stages = Vec::new();
+    for (name, text) in &new.stages {
+        let status = match old_stages.get(name) {
+            None => "added",
+            Some(old_text) if *old_text != text => "changed",
+            Some(_) => "unchanged",
+        };
+        stages.push(StageChange { name: name.clone(), status });
+    }
+    for (name, _) in &old.stages {
+        if !new_stages.contains_key(name) {
+            stages.push(StageChange { name: name.clone(), status: "removed" });
+        }
+    }
+
+    Diff { unified, stages }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rendered(text: &str) -> Rendered {
+        let instructions = crate::dockerfile::DockerFileParser::new().push_with_lines(text.as_bytes(), true);
+        Rendered::new(text.to_owned(), &instructions)
+    }
+
+    #[test]
+    fn test_summary() {
+        let old = rendered("FROM a AS one\nRUN a\nFROM a AS two\nRUN b\nFROM a AS three\n");
+        assert_eq!(old.stages[1], ("two".to_owned(), "FROM a AS two\nRUN b".to_owned()));
+        let new = rendered("FROM a AS one\nRUN a\nFROM a AS two\nRUN c\nFROM a AS four\n");
+        let out = diff(&old, &new, "old", "new").to_string();
+
+        assert!(out.starts_with("--- old\n+++ new\n"));
+        assert!(out.contains("-RUN b\n") && out.contains("+RUN c\n"));
+        assert!(out.ends_with("Stages:\n  unchanged  one\n  changed    two\n  added      four\n  removed    three\n"));
+    }
+}
@@ -3,13 +3,14 @@ use std::io::BufRead;
 use circular_buffer::CircularBuffer;
 use winnow::{
     ascii::{alphanumeric0, alphanumeric1, line_ending, till_line_ending, Caseless}, combinator::{
-        alt, cut_err, delimited, eof, opt, preceded, repeat, repeat_till, separated,
+        alt, cut_err, delimited, eof, opt, preceded, repeat, repeat_till,
         seq, terminated, trace,
-    }, error::{ContextError, ErrMode, ParserError}, prelude::*, stream::Offset as _, token::{rest, take, take_till, take_until, take_while}, Partial
+    }, error::{ContextError, ErrMode, ParserError}, prelude::*, stream::Offset as _, token::{none_of, rest, take, take_till, take_while}, Partial
 };
 
 pub struct DockerFileParser {
     buffer: Box<CircularBuffer<4096, u8>>,
+    line: usize,
 }
 
 type Stream<'i> = Partial<&'i [u8]>;
@@ -20,6 +21,7 @@ type StreamSlice<'i> = <Stream<'i> as winnow::stream::Stream>::Slice;
 pub enum DockerFileInstruction {
     From { src: String, name: Option<String> },
     Other(String, String),
+    Comment(String),
     Strange(String),
 }
 
@@ -27,19 +29,32 @@ fn dockerfile_instructions<'i, E: ParserError<Stream<'i>>>(
     input: &mut Stream<'i>,
 ) -> ModalResult<Option<DockerFileInstruction>, E> {
     alt((
-        delimited(ws(0..), single_instruction, comment_line_end).map(Some),
-        comment_line_end.value(None),
+        preceded(ws(0..), single_instruction).map(Some),
+        comment_line,
         strange_line.map(Some),
     ))
     .parse_next(input)
 }
 
+fn comment_line<'i, E: ParserError<Stream<'i>>>(
+    input: &mut Stream<'i>,
+) -> ModalResult<Option<DockerFileInstruction>, E> {
+    let ending = alt((line_ending.void(), eof.void()));
+    trace(
+        "comment_line",
+        (ws(0..), opt(preceded(b"#", till_line_ending)), ending).map(|r| {
+            r.1.map(|c| DockerFileInstruction::Comment(String::from_utf8_lossy(c).trim().to_owned()))
+        }),
+    )
+    .parse_next(input)
+}
+
 fn single_instruction<'i, E: ParserError<Stream<'i>>>(
     input: &mut Stream<'i>,
 ) -> ModalResult<DockerFileInstruction, E> {
     trace(
         "single_instruction",
-        alt((from_instruction, other_instruction)),
+        alt((terminated(from_instruction, comment_line_end), other_instruction)),
     )
         .parse_next(input)
         .map_err(|e: ErrMode<E>| match e {
@@ -88,14 +103,12 @@ fn other_instruction<'i, E: ParserError<Stream<'i>>>(
     input: &mut Stream<'i>,
 ) -> ModalResult<DockerFileInstruction, E> {
     // For now, assume any instruction can use heredoc or backslashes for continuation
-    let heredoc = trace("heredoc", heredoc_start.flat_map(|eot| cut_err(heredoc_finish(eot))));
-    let line_parts = (take_until_end1, comment_line_end).take();
-    let lines = trace("lines", separated::<_, _, (), _, _, _, _>(1.., line_parts.void(), b"\\").take());
+    let heredoc = trace("heredoc", heredoc_start.flat_map(|(strip_tabs, eot)| cut_err(heredoc_finish(eot, strip_tabs))).take());
     let instr_name = terminated(alphanumeric0, ws(1..));
 
     trace(
         "other_instruction",
-        (instr_name, alt((heredoc, lines))).map(|r| {
+        (instr_name, alt((heredoc, instruction_lines))).map(|r| {
             DockerFileInstruction::Other(
                 String::from_utf8_lossy(r.0).into_owned(),
                 String::from_utf8_lossy(r.1).into_owned(),
@@ -105,28 +118,50 @@ fn other_instruction<'i, E: ParserError<Stream<'i>>>(
     .parse_next(input)
 }
 
-fn heredoc_start<'i, E: ParserError<Stream<'i>>>(input: &mut Stream<'i>) -> ModalResult<String, E> { 
-    let with_redirect_out = trace("heredoc_start_with_redirect",
-        (alphanumeric1, ws(0..), ">", take_till(1.., b"\r\n"), line_ending).map(|s| s.0)
-    );
-    let without_redirect_out = trace("heredoc_start_without_redirect",
-        terminated(alphanumeric1, (ws(0..), line_ending))
+fn instruction_lines<'i, E: ParserError<Stream<'i>>>(
+    input: &mut Stream<'i>,
+) -> ModalResult<&'i [u8], E> {
+    // Comments and empty lines are skipped between continued lines
+    let continued = (
+        till_line_ending.verify(|s: &[u8]| s.trim_ascii_end().ends_with(b"\\")),
+        line_ending,
     );
-    let heredoc_suffix = trace("heredoc_suffix", alt((with_redirect_out, without_redirect_out))
+    let skipped = (ws(0..), opt((b"#", till_line_ending)), line_ending);
+    let last = (till_line_ending, alt((line_ending.void(), eof.void())));
+
+    trace(
+        "instruction_lines",
+        (repeat::<_, _, (), _, _>(0.., (continued, repeat::<_, _, (), _, _>(0.., skipped))), last).take(),
+    )
+    .parse_next(input)
+}
+
+/// The heredoc name, and whether it was started with `<<-` to strip leading tabs.
+fn heredoc_start<'i, E: ParserError<Stream<'i>>>(input: &mut Stream<'i>) -> ModalResult<(bool, String), E> {
+    let heredoc_name = trace("heredoc_name", alt((
+        delimited(b"\"", alphanumeric1, b"\""),
+        delimited(b"'", alphanumeric1, b"'"),
+        alphanumeric1,
+    ))
         .map(|s| String::from_utf8_lossy(s).into_owned()));
+    let before = repeat_till::<_, _, (), _, _, _, _>(0.., none_of([b'\r', b'\n']), b"<<");
 
     trace(
         "heredoc_start",
-        preceded((take_until(0.., "<<"), "<<"), heredoc_suffix)
+        terminated(
+            (preceded(before, opt(b"-").map(|d| d.is_some())), heredoc_name),
+            (till_line_ending, line_ending),
+        )
     ).parse_next(input)
 }
 
-fn heredoc_finish<'i, E>(fin: String) -> impl Parser<Stream<'i>, StreamSlice<'i>, E>
+fn heredoc_finish<'i, E>(fin: String, strip_tabs: bool) -> impl Parser<Stream<'i>, StreamSlice<'i>, E>
 where
     E: ParserError<Stream<'i>>,
 {
     let line = (till_line_ending, line_ending).take();
-    let end_line = (literal_owned(fin.into_bytes()), line_ending).void();
+    let tabs = take_while(0.., b'\t').verify(move |t: &[u8]| strip_tabs || t.is_empty());
+    let end_line = (tabs, literal_owned(fin.into_bytes()), alt((line_ending.void(), eof.void()))).void();
     trace(
         "heredoc_finish",
         repeat_till::<_, _, (), _, _, _, _>(0.., line, end_line).map(|r| r.0).take()
@@ -190,10 +225,17 @@ impl DockerFileParser {
     pub fn new() -> Self {
         Self {
             buffer: Box::new(CircularBuffer::new()),
+            line: 1,
         }
     }
 
+    #[cfg(test)]
     pub fn push(&mut self, data: &[u8], eof: bool) -> Vec<DockerFileInstruction> {
+        self.push_with_lines(data, eof).into_iter().map(|(_, i)| i).collect()
+    }
+
+    /// Like `push`, but also returns the line number each instruction starts on.
+    pub fn push_with_lines(&mut self, data: &[u8], eof: bool) -> Vec<(usize, DockerFileInstruction)> {
         let mut results = Vec::new();
         self.buffer.extend(data);
 
@@ -211,9 +253,11 @@ impl DockerFileParser {
             match dockerfile_instructions::<ContextError>.parse_next(&mut input) {
                 Ok(value) => {
                     if let Some(value) = value {
-                        results.push(value);
+                        results.push((self.line, value));
                     }
-                    consumed += input.offset_from(&start);
+                    let len = input.offset_from(&start);
+                    self.line += buffer[consumed..consumed + len].iter().filter(|b| **b == b'\n').count();
+                    consumed += len;
 
                     if eof && input.is_empty() {
                         break;
@@ -324,8 +368,92 @@ mod tests {
         assert_eq!(literal_owned::<ContextError>(b"abc".into()).parse_peek(Stream::new(b"abcd")), Ok((Stream::new(b"d"), b"abc" as &[u8])));
     }
 
+    #[test]
+    fn test_lines() {
+        let mut parser = DockerFileParser::new();
+        let mut items = parser.push_with_lines(b"# syntax=docker/dockerfile:1\n\nFROM src AS a\nRUN a \\\n  b\n", false);
+        items.extend(parser.push_with_lines(b"RUN <<EOT\necho\nEOT\nCOPY --from=a / /\n", true));
+        assert_eq!(
+            items,
+            vec![
+                (1, DockerFileInstruction::Comment("syntax=docker/dockerfile:1".into())),
+                (3, DockerFileInstruction::From { src: "src".into(), name: Some("a".into()) }),
+                (4, DockerFileInstruction::Other("RUN".into(), "a \\\n  b\n".into())),
+                (6, DockerFileInstruction::Other("RUN".into(), "<<EOT\necho\nEOT\n".into())),
+                (9, DockerFileInstruction::Other("COPY".into(), "--from=a / /\n".into())),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nested_from() {
+        assert_eq!(
+            docker_filter_from("FROM a AS a\nRUN <<EOT\n  echo '\nFROM meow AS cat\n'\nEOT\nRUN echo \\\nFROM meow AS cat \\\n > /a\nFROM b\n"),
+            vec![
+                FromInstr {
+                    src: "a".into(),
+                    name: Some("a".into())
+                },
+                FromInstr {
+                    src: "b".into(),
+                    name: None
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_partial_heredoc() {
+        let mut parser = DockerFileParser::new();
+        assert_eq!(parser.push(b"COPY <<EOT /a\nFROM x\n", false), vec![]);
+        assert_eq!(
+            parser.push(b"EOT\nRUN a\nRUN b", true),
+            vec![
+                DockerFileInstruction::Other("COPY".into(), "<<EOT /a\nFROM x\nEOT\n".into()),
+                DockerFileInstruction::Other("RUN".into(), "a\n".into()),
+                DockerFileInstruction::Other("RUN".into(), "b".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_continuation() {
+        let mut parser = DockerFileParser::new();
+        assert_eq!(
+            parser.push(b"RUN a \\\n# note\n\n  && b \\  \n  && c # not a comment\nRUN d\n", true),
+            vec![
+                DockerFileInstruction::Other("RUN".into(), "a \\\n# note\n\n  && b \\  \n  && c # not a comment\n".into()),
+                DockerFileInstruction::Other("RUN".into(), "d\n".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_heredoc_variants() {
+        let mut parser = DockerFileParser::new();
+        assert_eq!(
+            parser.push(b"RUN <<-\"EOT\"\n\techo\n\tEOT\nCOPY <<'A' /a\nx\nA", true),
+            vec![
+                DockerFileInstruction::Other("RUN".into(), "<<-\"EOT\"\n\techo\n\tEOT\n".into()),
+                DockerFileInstruction::Other("COPY".into(), "<<'A' /a\nx\nA".into()),
+            ]
+        );
+
+        // Only <<- strips tabs from the end line
+        let mut parser = DockerFileParser::new();
+        assert_eq!(
+            parser.push(b"RUN <<EOT\n\tEOT\nEOT\nRUN b\n", true),
+            vec![
+                DockerFileInstruction::Other("RUN".into(), "<<EOT\n\tEOT\nEOT\n".into()),
+                DockerFileInstruction::Other("RUN".into(), "b\n".into()),
+            ]
+        );
+    }
+
     #[test]
     fn test_heredoc_start() {
-        assert_eq!(heredoc_start::<ContextError>.parse_peek(Stream::new(b"<<ABC\n")).map(|t| t.1), Ok("ABC".into()));
+        assert_eq!(heredoc_start::<ContextError>.parse_peek(Stream::new(b"<<ABC\n")).map(|t| t.1), Ok((false, "ABC".into())));
+        assert_eq!(heredoc_start::<ContextError>.parse_peek(Stream::new(b"cat <<-\"ABC\" > /a\n")).map(|t| t.1), Ok((true, "ABC".into())));
+        assert!(heredoc_start::<ContextError>.parse_peek(Stream::new(b"echo a\nRUN <<ABC\n")).is_err());
     }
 }
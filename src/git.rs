@@ -0,0 +1,148 @@
+use std::{fmt, path::{Path, PathBuf}, process::Command, sync::{Arc, OnceLock}};
+
+use minijinja::{value::Object, Value};
+
+use crate::time::DateTime;
+
+/// Facts about the commit checked out in a local repository.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Info {
+    commit: String,
+    short_commit: String,
+    branch: Option<String>,
+    tag: Option<String>,
+    describe: String,
+    is_dirty: bool,
+    commit_time: i64,
+}
+
+impl Info {
+    fn read(dir: &Path) -> Option<Self> {
+        Some(Self {
+            commit: git(dir, &["rev-parse", "HEAD"])?,
+            short_commit: git(dir, &["rev-parse", "--short", "HEAD"])?,
+            branch: git(dir, &["symbolic-ref", "--short", "-q", "HEAD"]),
+            tag: git(dir, &["describe", "--tags", "--exact-match", "HEAD"]),
+            describe: git(dir, &["describe", "--tags", "--always", "--dirty"])?,
+            is_dirty: !git(dir, &["status", "--porcelain", "--untracked-files=no"])?.is_empty(),
+            commit_time: git(dir, &["show", "-s", "--format=%ct", "HEAD"])?.parse().ok()?,
+        })
+    }
+}
+
+/// Run a git command, returning its trimmed output if it succeeded.
+fn git(dir: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .ok()?;
+
+    output.status.success().then(|| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// `bern.git`, for the repository containing the context.
+///
+/// Everything is `none` outside of a repository.
+#[derive(Debug)]
+pub struct Git {
+    dir: PathBuf,
+    info: OnceLock<Option<Info>>,
+}
+
+impl Git {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_owned(),
+            info: OnceLock::new(),
+        }
+    }
+
+    fn info(&self) -> Option<&Info> {
+        self.info.get_or_init(|| Info::read(&self.dir)).as_ref()
+    }
+
+    /// `git describe` of the commit, if in a repository.
+    pub fn describe(&self) -> Option<String> {
+        Some(self.info()?.describe.clone())
+    }
+}
+
+impl Object for Git {
+    fn get_value(self: &Arc<Self>, key: &Value) -> Option<Value> {
+        let info = self.info();
+        let value = match key.as_str()? {
+            "commit" => info.map(|i| Value::from(&i.commit)),
+            "short_commit" => info.map(|i| Value::from(&i.short_commit)),
+            "branch" => info.map(|i| Value::from(i.branch.clone())),
+            "tag" => info.map(|i| Value::from(i.tag.clone())),
+            "describe" => info.map(|i| Value::from(&i.describe)),
+            "is_dirty" => info.map(|i| Value::from(i.is_dirty)),
+            "commit_time" => info.and_then(|i| DateTime::from_timestamp(i.commit_time)).map(Value::from_object),
+            _ => return None,
+        };
+        // Known keys are none outside of a repository, but anything else is still undefined
+        Some(value.unwrap_or(Value::from(())))
+    }
+
+    fn render(self: &Arc<Self>, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.info() {
+            Some(info) => f.write_str(&info.describe),
+            None => f.write_str("none"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn run(dir: &Path, args: &[&str]) {
+        let status = Command::new("git")
+            .current_dir(dir)
+            .args(["-c", "user.name=bern", "-c", "user.email=bern@example.com", "-c", "commit.gpgsign=false", "-c", "tag.gpgsign=false"])
+            .args(args)
+            .output()
+            .unwrap()
+            .status;
+        assert!(status.success(), "git {args:?} failed");
+    }
+
+    #[test]
+    fn test_info() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(Info::read(dir.path()), None);
+
+        run(dir.path(), &["init", "-q", "-b", "main"]);
+        fs::write(dir.path().join("a"), "1").unwrap();
+        run(dir.path(), &["add", "a"]);
+        run(dir.path(), &["commit", "-q", "-m", "first"]);
+        run(dir.path(), &["tag", "v1.0.0"]);
+
+        let info = Info::read(dir.path()).unwrap();
+        assert_eq!(info.commit.len(), 40);
+        assert!(info.commit.starts_with(&info.short_commit));
+        assert_eq!(info.branch.as_deref(), Some("main"));
+        assert_eq!(info.tag.as_deref(), Some("v1.0.0"));
+        assert_eq!(info.describe, "v1.0.0");
+        assert!(!info.is_dirty);
+        assert!(info.commit_time > 0);
+
+        fs::write(dir.path().join("a"), "2").unwrap();
+        let info = Info::read(dir.path()).unwrap();
+        assert!(info.is_dirty);
+        assert_eq!(info.describe, "v1.0.0-dirty");
+    }
+
+    #[test]
+    fn test_outside_repository() {
+        let dir = tempfile::tempdir().unwrap();
+        let git = Arc::new(Git::new(dir.path()));
+
+        assert!(git.get_value(&Value::from("commit")).is_some_and(|v| v.is_none()));
+        assert!(git.get_value(&Value::from("commit_time")).is_some_and(|v| v.is_none()));
+        assert_eq!(git.get_value(&Value::from("comit")), None);
+    }
+}
@@ -0,0 +1,286 @@
+use std::{collections::{BTreeSet, HashSet}, fmt::Write as _};
+
+use anyhow::anyhow;
+
+use crate::dockerfile::DockerFileInstruction;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum GraphSyntax {
+    #[default]
+    Dot,
+    Mermaid,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Node {
+    Stage(usize),
+    Image(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum EdgeKind {
+    From,
+    Copy,
+    Mount,
+}
+
+impl EdgeKind {
+    fn label(&self) -> &'static str {
+        match self {
+            Self::From => "from",
+            Self::Copy => "copy",
+            Self::Mount => "mount",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Stage {
+    name: Option<String>,
+}
+
+/// Dependencies between the stages of a Dockerfile.
+#[derive(Debug, Default)]
+pub struct StageGraph {
+    stages: Vec<Stage>,
+    images: Vec<String>,
+    /// Edges from a dependency to the stage that uses it
+    edges: BTreeSet<(Node, usize, EdgeKind)>,
+}
+
+#[derive(Debug, Default)]
+struct Highlight {
+    targets: HashSet<usize>,
+    dependencies: HashSet<usize>,
+}
+
+impl StageGraph {
+    pub fn from_instructions(instructions: &[(usize, DockerFileInstruction)]) -> Self {
+        let mut graph = Self::default();
+
+        for (_, instr) in instructions {
+            match instr {
+                DockerFileInstruction::From { src, name } => {
+                    let node = graph.resolve(src);
+                    graph.stages.push(Stage { name: name.clone() });
+                    graph.add_edge(node, EdgeKind::From);
+                },
+                DockerFileInstruction::Other(name, args) if !graph.stages.is_empty() => {
+                    for (kind, reference) in references(name, args) {
+                        let node = graph.resolve(&reference);
+                        graph.add_edge(node, kind);
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        graph
+    }
+
+    fn add_edge(&mut self, node: Node, kind: EdgeKind) {
+        let stage = self.stages.len() - 1;
+        self.edges.insert((node, stage, kind));
+    }
+
+    fn find_stage(&self, reference: &str) -> Option<usize> {
+        self.stages.iter()
+            .position(|s| s.name.as_ref().is_some_and(|n| n.eq_ignore_ascii_case(reference)))
+            .or_else(|| reference.parse().ok().filter(|idx| *idx < self.stages.len()))
+    }
+
+    fn resolve(&mut self, reference: &str) -> Node {
+        if let Some(idx) = self.find_stage(reference) {
+            return Node::Stage(idx);
+        }
+
+        let idx = self.images.iter().position(|i| i == reference).unwrap_or_else(|| {
+            self.images.push(reference.to_owned());
+            self.images.len() - 1
+        });
+        Node::Image(idx)
+    }
+
+    fn highlight(&self, targets: &[String]) -> anyhow::Result<Highlight> {
+        let mut highlight = Highlight::default();
+        let mut pending = Vec::new();
+
+        for target in targets {
+            let idx = self.find_stage(target).ok_or_else(|| anyhow!("Unknown target {target}"))?;
+            highlight.targets.insert(idx);
+            pending.push(idx);
+        }
+
+        while let Some(stage) = pending.pop() {
+            for (node, _, _) in self.edges.iter().filter(|e| e.1 == stage) {
+                if let Node::Stage(dep) = node
+                    && highlight.dependencies.insert(*dep)
+                {
+                    pending.push(*dep);
+                }
+            }
+        }
+
+        Ok(highlight)
+    }
+
+    fn label(&self, node: Node) -> String {
+        match node {
+            Node::Stage(idx) => match &self.stages[idx].name {
+                Some(name) => name.clone(),
+                None => format!("stage {idx}"),
+            },
+            Node::Image(idx) => self.images[idx].clone(),
+        }
+    }
+
+    fn node_id(node: Node) -> String {
+        match node {
+            Node::Stage(idx) => format!("s{idx}"),
+            Node::Image(idx) => format!("i{idx}"),
+        }
+    }
+
+    fn nodes(&self) -> impl Iterator<Item = Node> {
+        (0..self.stages.len()).map(Node::Stage).chain((0..self.images.len()).map(Node::Image))
+    }
+
+    /// Render as Graphviz DOT, highlighting the given targets and their dependencies.
+    pub fn to_dot(&self, targets: &[String]) -> anyhow::Result<String> {
+        let highlight = self.highlight(targets)?;
+        let mut out = String::from("digraph stages {\n    rankdir=LR;\n    node [shape=box];\n");
+
+        for node in self.nodes() {
+            let mut attrs = vec![format!("label={}", dot_quote(&self.label(node)))];
+            match node {
+                Node::Image(_) => attrs.push("shape=ellipse, style=dashed".to_owned()),
+                Node::Stage(idx) if highlight.targets.contains(&idx) => attrs.push("style=\"filled,bold\", fillcolor=gold".to_owned()),
+                Node::Stage(idx) if highlight.dependencies.contains(&idx) => attrs.push("style=filled, fillcolor=lightyellow".to_owned()),
+                Node::Stage(_) => {},
+            }
+            writeln!(out, "    {} [{}];", Self::node_id(node), attrs.join(", "))?;
+        }
+
+        for (node, stage, kind) in &self.edges {
+            writeln!(out, "    {} -> {} [label={}];", Self::node_id(*node), Self::node_id(Node::Stage(*stage)), kind.label())?;
+        }
+
+        out.push_str("}\n");
+        Ok(out)
+    }
+
+    /// Render as a Mermaid flowchart, highlighting the given targets and their dependencies.
+    pub fn to_mermaid(&self, targets: &[String]) -> anyhow::Result<String> {
+        let highlight = self.highlight(targets)?;
+        let mut out = String::from("flowchart LR\n");
+
+        for node in self.nodes() {
+            let label = mermaid_quote(&self.label(node));
+            match node {
+                Node::Stage(_) => writeln!(out, "    {}[{label}]", Self::node_id(node))?,
+                Node::Image(_) => writeln!(out, "    {}([{label}])", Self::node_id(node))?,
+            }
+        }
+
+        for (node, stage, kind) in &self.edges {
+            writeln!(out, "    {} -->|{}| {}", Self::node_id(*node), kind.label(), Self::node_id(Node::Stage(*stage)))?;
+        }
+
+        let dependencies: HashSet<_> = highlight.dependencies.difference(&highlight.targets).copied().collect();
+        for (class, style, stages) in [
+            ("target", "fill:#ffd700,stroke-width:2px", &highlight.targets),
+            ("dependency", "fill:#ffffe0", &dependencies),
+        ] {
+            if stages.is_empty() {
+                continue;
+            }
+
+            let mut stages: Vec<_> = stages.iter().copied().collect();
+            stages.sort();
+            let ids: Vec<_> = stages.into_iter().map(|s| Self::node_id(Node::Stage(s))).collect();
+            writeln!(out, "    classDef {class} {style}")?;
+            writeln!(out, "    class {} {class}", ids.join(","))?;
+        }
+
+        Ok(out)
+    }
+}
+
+/// Find stage references in the leading flags of an instruction.
+fn references(name: &str, args: &str) -> Vec<(EdgeKind, String)> {
+    let name = name.to_ascii_uppercase();
+    let flags = args.split_whitespace()
+        .filter(|a| *a != "\\")
+        .take_while(|a| a.starts_with("--"));
+
+    let mut refs = Vec::new();
+    for flag in flags {
+        if name == "COPY" && let Some(from) = flag.strip_prefix("--from=") {
+            refs.push((EdgeKind::Copy, from.to_owned()));
+        } else if name == "RUN" && let Some(mount) = flag.strip_prefix("--mount=") {
+            let from = mount.split(',').find_map(|opt| opt.strip_prefix("from="));
+            if let Some(from) = from {
+                refs.push((EdgeKind::Mount, from.to_owned()));
+            }
+        }
+    }
+    refs
+}
+
+fn dot_quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn mermaid_quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('"', "#quot;"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dockerfile::DockerFileParser;
+
+    fn graph(contents: &str) -> StageGraph {
+        StageGraph::from_instructions(&DockerFileParser::new().push_with_lines(contents.as_bytes(), true))
+    }
+
+    const DOCKERFILE: &str = "\
+FROM rust:1 AS deps
+FROM deps AS build
+RUN --mount=type=cache,target=/cache,from=deps cargo build
+FROM rust:1 AS unrelated
+FROM scratch AS export
+COPY --from=build /out /
+COPY --from=0 /a /
+";
+
+    #[test]
+    fn test_edges() {
+        let graph = graph(DOCKERFILE);
+        assert_eq!(graph.images, vec!["rust:1".to_owned(), "scratch".to_owned()]);
+        assert_eq!(graph.edges.iter().copied().collect::<Vec<_>>(), vec![
+            (Node::Stage(0), 1, EdgeKind::From),
+            (Node::Stage(0), 1, EdgeKind::Mount),
+            (Node::Stage(0), 3, EdgeKind::Copy),
+            (Node::Stage(1), 3, EdgeKind::Copy),
+            (Node::Image(0), 0, EdgeKind::From),
+            (Node::Image(0), 2, EdgeKind::From),
+            (Node::Image(1), 3, EdgeKind::From),
+        ]);
+    }
+
+    #[test]
+    fn test_highlight() {
+        let graph = graph(DOCKERFILE);
+        let highlight = graph.highlight(&["export".to_owned()]).unwrap();
+        assert_eq!(highlight.targets, HashSet::from([3]));
+        assert_eq!(highlight.dependencies, HashSet::from([0, 1]));
+        assert!(graph.highlight(&["missing".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn test_references() {
+        assert_eq!(references("COPY", "--link --from=a /a /b\n"), vec![(EdgeKind::Copy, "a".to_owned())]);
+        assert_eq!(references("RUN", "echo --mount=from=a\n"), vec![]);
+    }
+}
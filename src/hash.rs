@@ -0,0 +1,110 @@
+use std::{fmt::Write as _, fs, path::Path};
+
+use anyhow::{bail, Context as _};
+use sha2::{Digest, Sha224, Sha256, Sha384, Sha512};
+
+/// A hash algorithm, by the name templates use for it.
+#[derive(Debug, Clone, Copy)]
+pub enum Algo {
+    Sha224,
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl Algo {
+    pub fn parse(name: &str) -> anyhow::Result<Self> {
+        Ok(match name {
+            "sha224" => Self::Sha224,
+            "sha256" => Self::Sha256,
+            "sha384" => Self::Sha384,
+            "sha512" => Self::Sha512,
+            _ => bail!("Unknown hash algorithm {name}, expected sha224, sha256, sha384 or sha512"),
+        })
+    }
+
+    fn hasher(&self) -> Box<dyn sha2::digest::DynDigest> {
+        match self {
+            Self::Sha224 => Box::new(Sha224::new()),
+            Self::Sha256 => Box::new(Sha256::new()),
+            Self::Sha384 => Box::new(Sha384::new()),
+            Self::Sha512 => Box::new(Sha512::new()),
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::with_capacity(bytes.len() * 2), |mut s, b| {
+        let _ = write!(s, "{b:02x}");
+        s
+    })
+}
+
+/// Hex digest of some bytes.
+pub fn digest(algo: Algo, data: &[u8]) -> String {
+    let mut hasher = algo.hasher();
+    hasher.update(data);
+    hex(&hasher.finalize())
+}
+
+/// Hex digest of a file's contents.
+pub fn file(algo: Algo, path: &Path) -> anyhow::Result<String> {
+    let data = fs::read(path).with_context(|| format!("Failed to read file: {}", path.display()))?;
+    Ok(digest(algo, &data))
+}
+
+/// Hex digest of a set of files, covering both their names and contents.
+///
+/// `files` are pairs of the name to hash and the path to read, and are hashed in the order
+/// given, so they should be sorted.
+pub fn files<P: AsRef<Path>>(algo: Algo, files: &[(String, P)]) -> anyhow::Result<String> {
+    let mut hasher = algo.hasher();
+    for (name, path) in files {
+        hasher.update(name.as_bytes());
+        hasher.update(b"\0");
+        hasher.update(file(algo, path.as_ref())?.as_bytes());
+        hasher.update(b"\n");
+    }
+    Ok(hex(&hasher.finalize()))
+}
+
+/// Seed for `bern.random_id`, so that the same build id gives the same ids.
+pub fn seed(build_id: &str) -> u64 {
+    let digest = Sha256::digest(build_id.as_bytes());
+    u64::from_le_bytes(digest[..8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_digest() {
+        assert_eq!(digest(Algo::Sha256, b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(digest(Algo::Sha224, b"abc"), "23097d223405d8228642a477bda255b32aadbce4bda0b3f7e36c9da7");
+        assert!(Algo::parse("md5").is_err());
+    }
+
+    #[test]
+    fn test_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a"), "one").unwrap();
+        fs::write(dir.path().join("b"), "two").unwrap();
+
+        let hash = |names: &[&str]| {
+            let paths: Vec<_> = names.iter().map(|n| (n.to_string(), dir.path().join(n))).collect();
+            files(Algo::Sha256, &paths).unwrap()
+        };
+        let both = hash(&["a", "b"]);
+        assert_eq!(both, hash(&["a", "b"]));
+        assert_ne!(both, hash(&["a"]));
+
+        // Renaming a file changes the hash, even with the same contents
+        fs::rename(dir.path().join("b"), dir.path().join("c")).unwrap();
+        assert_ne!(both, hash(&["a", "c"]));
+
+        let before = hash(&["a"]);
+        fs::write(dir.path().join("a"), "changed").unwrap();
+        assert_ne!(before, hash(&["a"]));
+    }
+}
@@ -0,0 +1,66 @@
+use std::path::Path;
+
+use serde::Serialize;
+
+/// `bern.host`, the machine bern runs on.
+#[derive(Debug, Serialize)]
+pub struct Host {
+    pub os: &'static str,
+    pub arch: &'static str,
+    /// `arch` as Docker spells it, as in `TARGETARCH`
+    pub docker_arch: &'static str,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub cpu_count: usize,
+    /// The command used to run builds, `docker` or `podman`
+    pub engine: Option<String>,
+}
+
+impl Host {
+    pub fn detect(engine: Option<&Path>) -> Self {
+        #[cfg(unix)]
+        let (uid, gid) = (
+            Some(rustix::process::getuid().as_raw()),
+            Some(rustix::process::getgid().as_raw()),
+        );
+        #[cfg(not(unix))]
+        let (uid, gid) = (None, None);
+
+        Self {
+            os: std::env::consts::OS,
+            arch: std::env::consts::ARCH,
+            docker_arch: docker_arch(std::env::consts::ARCH),
+            uid,
+            gid,
+            cpu_count: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            engine: engine.and_then(|e| e.file_stem()).map(|e| e.to_string_lossy().into_owned()),
+        }
+    }
+}
+
+/// Docker's name for a Rust target architecture.
+fn docker_arch(arch: &'static str) -> &'static str {
+    match arch {
+        "x86_64" => "amd64",
+        "x86" => "386",
+        "aarch64" => "arm64",
+        "powerpc64" if cfg!(target_endian = "little") => "ppc64le",
+        "loongarch64" => "loong64",
+        arch => arch,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        let host = Host::detect(Some(Path::new("/usr/bin/podman")));
+        assert_eq!(host.engine.as_deref(), Some("podman"));
+        assert!(host.cpu_count >= 1);
+        assert_eq!(docker_arch("x86_64"), "amd64");
+        assert_eq!(docker_arch("aarch64"), "arm64");
+        assert_eq!(docker_arch("s390x"), "s390x");
+    }
+}
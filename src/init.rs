@@ -0,0 +1,158 @@
+use std::{fs, path::Path};
+
+use anyhow::{bail, Context as _};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ProjectKind {
+    Rust,
+    Node,
+    Python,
+    Go,
+    Generic,
+}
+
+impl ProjectKind {
+    /// Guess the kind of project from the files in the given directory.
+    pub fn detect(root: &Path) -> Self {
+        [
+            ("Cargo.toml", Self::Rust),
+            ("package.json", Self::Node),
+            ("pyproject.toml", Self::Python),
+            ("go.mod", Self::Go),
+        ]
+            .into_iter()
+            .find(|(name, _)| root.join(name).is_file())
+            .map(|(_, kind)| kind)
+            .unwrap_or(Self::Generic)
+    }
+
+    fn builder(&self, root: &Path) -> String {
+        match self {
+            Self::Rust => {
+                let name = cargo_package_name(root).unwrap_or_else(|| "app".to_owned());
+                format!("\
+FROM docker.io/library/rust:1 AS builder
+WORKDIR /src
+COPY . .
+RUN cargo build --release --locked
+
+FROM scratch AS export
+COPY --from=builder /src/target/release/{name} /
+")
+            },
+            Self::Node => "\
+FROM docker.io/library/node:22 AS builder
+WORKDIR /src
+COPY package*.json ./
+RUN npm ci
+COPY . .
+RUN npm run build
+
+FROM scratch AS export
+COPY --from=builder /src/dist /
+".to_owned(),
+            Self::Python => "\
+FROM docker.io/library/python:3 AS builder
+WORKDIR /src
+COPY . .
+RUN pip wheel --no-deps --wheel-dir /dist .
+
+FROM scratch AS export
+COPY --from=builder /dist /
+".to_owned(),
+            Self::Go => "\
+FROM docker.io/library/golang:1 AS builder
+WORKDIR /src
+COPY go.* ./
+RUN go mod download
+COPY . .
+RUN CGO_ENABLED=0 go build -o /out/ ./...
+
+FROM scratch AS export
+COPY --from=builder /out /
+".to_owned(),
+            Self::Generic => "\
+FROM docker.io/library/alpine:3 AS builder
+WORKDIR /src
+COPY . .
+RUN mkdir -p /out && cp -r /src/. /out/
+
+FROM scratch AS export
+COPY --from=builder /out /
+".to_owned(),
+        }
+    }
+
+    fn dockerignore(&self) -> &'static str {
+        match self {
+            Self::Rust => ".git\ntarget\n",
+            Self::Node => ".git\nnode_modules\ndist\n",
+            Self::Python => ".git\n.venv\n__pycache__\n*.egg-info\nbuild\ndist\n",
+            Self::Go => ".git\n",
+            Self::Generic => ".git\n",
+        }
+    }
+}
+
+fn cargo_package_name(root: &Path) -> Option<String> {
+    let manifest: toml::Table = toml::from_str(&fs::read_to_string(root.join("Cargo.toml")).ok()?).ok()?;
+    Some(manifest.get("package")?.get("name")?.as_str()?.to_owned())
+}
+
+fn version_require() -> String {
+    let version = semver::Version::parse(env!("CARGO_PKG_VERSION")).expect("valid package version");
+    format!(">={}.{}", version.major, version.minor)
+}
+
+fn template(kind: ProjectKind, root: &Path) -> String {
+    format!(
+        "{{# Run `bern --output out` to build and export the results to ./out #}}\n\
+        {{% do bern.version_require(\"{}\") %}}\n\n{}",
+        version_require(),
+        kind.builder(root),
+    )
+}
+
+fn write_file(path: &Path, contents: &str) -> anyhow::Result<()> {
+    fs::write(path, contents).with_context(|| format!("Failed to write file: {}", path.display()))?;
+    eprintln!("Wrote {}", path.display());
+
+    Ok(())
+}
+
+/// Write a starter template and matching .dockerignore for the project in `root`.
+pub fn init(file: &Path, root: &Path, kind: Option<ProjectKind>, force: bool) -> anyhow::Result<()> {
+    let kind = kind.unwrap_or_else(|| ProjectKind::detect(root));
+    let dockerignore = root.join(".dockerignore");
+
+    if !force {
+        for path in [file, &dockerignore] {
+            if path.exists() {
+                bail!("{} already exists, use --force to overwrite", path.display());
+            }
+        }
+    }
+
+    write_file(file, &template(kind, root))?;
+    write_file(&dockerignore, kind.dockerignore())?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(ProjectKind::detect(dir.path()), ProjectKind::Generic);
+
+        fs::write(dir.path().join("go.mod"), "module example.com/app\n").unwrap();
+        assert_eq!(ProjectKind::detect(dir.path()), ProjectKind::Go);
+
+        fs::write(dir.path().join("Cargo.toml"), "[package]\nname = \"meow\"\n").unwrap();
+        assert_eq!(ProjectKind::detect(dir.path()), ProjectKind::Rust);
+        assert_eq!(cargo_package_name(dir.path()).as_deref(), Some("meow"));
+    }
+}
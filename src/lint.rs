@@ -0,0 +1,339 @@
+use std::{collections::{HashMap, HashSet}, fmt};
+
+use anyhow::bail;
+
+use crate::{config::LintSettings, dockerfile::DockerFileInstruction};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Warning => f.write_str("warning"),
+            Self::Error => f.write_str("error"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Rule {
+    pub name: &'static str,
+    pub severity: Severity,
+    pub default: bool,
+    pub description: &'static str,
+}
+
+pub const RULES: &[Rule] = &[
+    Rule {
+        name: "latest-tag",
+        severity: Severity::Warning,
+        default: true,
+        description: "Base image uses the latest tag, or no tag at all",
+    },
+    Rule {
+        name: "pinned-digest",
+        severity: Severity::Warning,
+        default: false,
+        description: "Base image is not pinned to a digest",
+    },
+    Rule {
+        name: "apt-no-recommends",
+        severity: Severity::Warning,
+        default: true,
+        description: "apt-get install without --no-install-recommends",
+    },
+    Rule {
+        name: "heredoc-syntax",
+        severity: Severity::Warning,
+        default: true,
+        description: "Heredoc used without a # syntax= parser directive",
+    },
+    Rule {
+        name: "maintainer",
+        severity: Severity::Warning,
+        default: true,
+        description: "Deprecated MAINTAINER instruction",
+    },
+    Rule {
+        name: "duplicate-stage",
+        severity: Severity::Error,
+        default: true,
+        description: "Stage name is defined more than once",
+    },
+    Rule {
+        name: "strange-line",
+        severity: Severity::Error,
+        default: true,
+        description: "Line could not be understood",
+    },
+];
+
+fn rule(name: &str) -> Option<&'static Rule> {
+    RULES.iter().find(|r| r.name == name)
+}
+
+#[derive(Debug)]
+pub struct Finding {
+    pub rule: &'static Rule,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}[{}]: {}", self.rule.severity, self.rule.name, self.message)
+    }
+}
+
+#[derive(Default)]
+struct Linter {
+    findings: Vec<Finding>,
+    stages: HashMap<String, usize>,
+    ignore_next: HashSet<String>,
+    ignored: HashMap<usize, HashSet<String>>,
+    directive_line: Option<usize>,
+    syntax_directive: bool,
+    misplaced_syntax: Option<usize>,
+    first_heredoc: Option<usize>,
+}
+
+impl Linter {
+    fn new() -> Self {
+        Self {
+            directive_line: Some(1),
+            ..Default::default()
+        }
+    }
+
+    fn report(&mut self, name: &str, line: usize, message: String) {
+        let rule = rule(name).expect("known lint rule");
+        self.findings.push(Finding { rule, line, message });
+    }
+
+    fn check(&mut self, line: usize, instr: &DockerFileInstruction) {
+        if let DockerFileInstruction::Comment(comment) = instr {
+            self.check_comment(line, comment);
+            return;
+        }
+
+        self.directive_line = None;
+        if !self.ignore_next.is_empty() {
+            self.ignored.insert(line, std::mem::take(&mut self.ignore_next));
+        }
+
+        match instr {
+            DockerFileInstruction::From { src, name } => self.check_from(line, src, name.as_deref()),
+            DockerFileInstruction::Other(name, args) => self.check_other(line, name, args),
+            DockerFileInstruction::Strange(s) => self.report("strange-line", line, format!("Could not understand line: {s}")),
+            DockerFileInstruction::Comment(_) => {},
+        }
+    }
+
+    fn check_comment(&mut self, line: usize, comment: &str) {
+        let directive = comment.split_once('=')
+            .map(|(k, _)| k.trim().to_ascii_lowercase())
+            .filter(|k| !k.is_empty() && k.chars().all(|c| c.is_ascii_alphanumeric()));
+
+        if directive.as_deref() == Some("syntax") {
+            if self.directive_line == Some(line) {
+                self.syntax_directive = true;
+            } else {
+                self.misplaced_syntax.get_or_insert(line);
+            }
+        }
+
+        if directive.is_some() && self.directive_line == Some(line) {
+            self.directive_line = Some(line + 1);
+        } else {
+            self.directive_line = None;
+        }
+
+        if let Some(rest) = comment.strip_prefix("bern-lint:") {
+            for option in rest.split_whitespace() {
+                if let Some(names) = option.strip_prefix("ignore=") {
+                    self.ignore_next.extend(names.split(',').map(|n| n.trim().to_owned()));
+                }
+            }
+        }
+    }
+
+    fn check_from(&mut self, line: usize, src: &str, name: Option<&str>) {
+        let is_stage = self.stages.contains_key(&src.to_ascii_lowercase());
+        if !is_stage && src != "scratch" && !src.contains('$') && !src.contains('@') {
+            self.report("pinned-digest", line, format!("Base image {src} is not pinned to a digest"));
+
+            let image = src.rsplit_once('/').map(|s| s.1).unwrap_or(src);
+            match image.split_once(':') {
+                Some((_, "latest")) => self.report("latest-tag", line, format!("Base image {src} uses the latest tag")),
+                None => self.report("latest-tag", line, format!("Base image {src} has no tag, which implies latest")),
+                Some(_) => {},
+            }
+        }
+
+        if let Some(name) = name
+            && let Some(prev) = self.stages.insert(name.to_ascii_lowercase(), line)
+        {
+            self.report("duplicate-stage", line, format!("Stage {name} was already defined on line {prev}"));
+        }
+    }
+
+    fn check_other(&mut self, line: usize, name: &str, args: &str) {
+        let name = name.to_ascii_uppercase();
+
+        if name == "MAINTAINER" {
+            self.report("maintainer", line, "MAINTAINER is deprecated, use a LABEL instead".to_owned());
+        }
+
+        let first_line = args.lines().next().unwrap_or_default();
+        if matches!(name.as_str(), "RUN" | "COPY" | "ADD") && is_heredoc(first_line) {
+            self.first_heredoc.get_or_insert(line);
+        }
+
+        if name == "RUN" {
+            let words: Vec<_> = args.split_whitespace().collect();
+            if apt_installs(&words) && !words.contains(&"--no-install-recommends") {
+                self.report("apt-no-recommends", line, "apt-get install without --no-install-recommends".to_owned());
+            }
+        }
+    }
+
+    fn finish(mut self) -> Vec<Finding> {
+        if let Some(line) = self.first_heredoc && !self.syntax_directive {
+            let message = match self.misplaced_syntax {
+                Some(syntax_line) => format!("Heredoc used, but the # syntax= directive on line {syntax_line} is not at the top of the file"),
+                None => "Heredoc used without a # syntax= parser directive".to_owned(),
+            };
+            self.report("heredoc-syntax", line, message);
+        }
+
+        let ignored = self.ignored;
+        let mut findings: Vec<_> = self.findings.into_iter()
+            .filter(|f| !ignored.get(&f.line).is_some_and(|i| i.contains(f.rule.name)))
+            .collect();
+        findings.sort_by_key(|f| f.line);
+        findings
+    }
+}
+
+/// Whether `apt-get` or `apt` is run with `install`, after any options.
+fn apt_installs(words: &[&str]) -> bool {
+    words.iter().enumerate()
+        .filter(|(_, w)| matches!(**w, "apt-get" | "apt"))
+        .any(|(idx, _)| {
+            let mut rest = words[idx + 1..].iter();
+            while let Some(word) = rest.next() {
+                match *word {
+                    // Options that take the next word as their value
+                    "-o" | "-c" | "-t" => { rest.next(); },
+                    w if w.starts_with('-') => {},
+                    w => return w == "install",
+                }
+            }
+            false
+        })
+}
+
+fn is_heredoc(line: &str) -> bool {
+    line.match_indices("<<").any(|(idx, _)| {
+        let rest = line[idx + 2..].trim_start_matches('-');
+        rest.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '"' || c == '\'')
+    })
+}
+
+fn enabled_rules(settings: &LintSettings) -> anyhow::Result<HashSet<&'static str>> {
+    for name in settings.enable.iter().chain(&settings.disable) {
+        if rule(name).is_none() {
+            bail!("Unknown lint rule: {name}");
+        }
+    }
+
+    Ok(RULES.iter()
+        .filter(|r| (r.default || settings.enable.iter().any(|n| n == r.name)) && !settings.disable.iter().any(|n| n == r.name))
+        .map(|r| r.name)
+        .collect())
+}
+
+/// Check rendered Dockerfile instructions against the enabled lint rules.
+pub fn lint(instructions: &[(usize, DockerFileInstruction)], settings: &LintSettings) -> anyhow::Result<Vec<Finding>> {
+    let enabled = enabled_rules(settings)?;
+
+    let mut linter = Linter::new();
+    for (line, instr) in instructions {
+        linter.check(*line, instr);
+    }
+
+    Ok(linter.finish().into_iter().filter(|f| enabled.contains(f.rule.name)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dockerfile::DockerFileParser;
+
+    fn lint_str(contents: &str, settings: &LintSettings) -> Vec<(usize, &'static str)> {
+        let instructions = DockerFileParser::new().push_with_lines(contents.as_bytes(), true);
+        lint(&instructions, settings).unwrap().into_iter().map(|f| (f.line, f.rule.name)).collect()
+    }
+
+    #[test]
+    fn test_rules() {
+        let findings = lint_str("\
+FROM alpine AS a
+FROM debian:latest AS b
+FROM docker.io/library/debian:stable AS c
+RUN apt-get install -y curl
+RUN apt-get install -y --no-install-recommends curl
+RUN apt-get -y install curl && apt -q -o Dpkg::Use-Pty=0 install git
+RUN apt-get -y update
+FROM a AS b
+MAINTAINER someone
+", &LintSettings::default());
+
+        assert_eq!(findings, vec![
+            (1, "latest-tag"),
+            (2, "latest-tag"),
+            (4, "apt-no-recommends"),
+            (6, "apt-no-recommends"),
+            (8, "duplicate-stage"),
+            (9, "maintainer"),
+        ]);
+    }
+
+    #[test]
+    fn test_settings() {
+        let settings = LintSettings {
+            enable: vec!["pinned-digest".into()],
+            disable: vec!["latest-tag".into()],
+        };
+        assert_eq!(lint_str("FROM alpine\nFROM alpine@sha256:abcd\nFROM scratch\n", &settings), vec![(1, "pinned-digest")]);
+
+        let settings = LintSettings {
+            enable: vec!["meow".into()],
+            ..Default::default()
+        };
+        assert!(lint(&[], &settings).is_err());
+    }
+
+    #[test]
+    fn test_ignore_comment() {
+        let findings = lint_str("\
+# bern-lint: ignore=latest-tag
+FROM alpine
+FROM debian
+", &LintSettings::default());
+
+        assert_eq!(findings, vec![(3, "latest-tag")]);
+    }
+
+    #[test]
+    fn test_heredoc_syntax() {
+        let heredoc = "FROM scratch\nRUN <<EOT\necho\nEOT\n";
+        assert_eq!(lint_str(heredoc, &LintSettings::default()), vec![(2, "heredoc-syntax")]);
+        assert_eq!(lint_str(&format!("# syntax=docker/dockerfile:1\n{heredoc}"), &LintSettings::default()), vec![]);
+        assert_eq!(lint_str(&format!("\n# syntax=docker/dockerfile:1\n{heredoc}"), &LintSettings::default()), vec![(4, "heredoc-syntax")]);
+    }
+}
@@ -1,89 +1,460 @@
-use std::{fs, io::{self, BufWriter}, path::PathBuf};
+use std::{collections::HashMap, fs, io::{self, BufWriter, Write as _}, path::{Path, PathBuf}};
 
-use clap::{Parser, Subcommand};
+use clap::{parser::ValueSource, ArgMatches, CommandFactory as _, FromArgMatches as _, Parser, Subcommand, ValueEnum};
+use clap_complete::ArgValueCompleter;
 
 mod build;
+mod complete;
+mod config;
+mod context;
+mod diagnostic;
+mod diff;
 mod dockerfile;
+mod git;
+mod graph;
+mod hash;
+mod host;
+mod init;
+mod lint;
+mod manifest;
+mod pty;
+mod sourcemap;
 mod template;
+mod time;
+mod vars;
+mod version;
+mod watch;
+
+const DEFAULT_FILE: &str = "Dockerfile.j2";
 
 #[derive(Clone, Debug, Parser)]
-#[clap(version)]
+#[clap(name = "bern", version)]
 struct Cli {
-    /// Docker template file
-    #[clap(long, short, default_value = "Dockerfile.j2")]
-    file: PathBuf,
+    /// Docker template file [default: Dockerfile.j2]
+    #[clap(long, short)]
+    file: Option<PathBuf>,
+
+    /// Config file [default: bern.toml next to the template file]
+    #[clap(long)]
+    config: Option<PathBuf>,
+
+    /// Build profile from the config file
+    #[clap(long, env = "BERN_PROFILE")]
+    profile: Option<String>,
+
+    /// Context directory [default: .]
+    #[clap(long)]
+    context: Option<PathBuf>,
+
+    /// Directory to search for included templates, after the context directory (multiple)
+    #[clap(long)]
+    template_path: Vec<PathBuf>,
+
+    /// TOML, YAML or JSON file to load into `vars` in templates, merged in order (multiple)
+    #[clap(long)]
+    vars_file: Vec<PathBuf>,
+
+    /// Environment variables that templates may read with bern.env, e.g. CI_* (multiple)
+    #[clap(long)]
+    allow_env: Vec<String>,
+
+    /// Seed for bern.random_id in templates [default: git describe of the context]
+    #[clap(long, env = "BERN_BUILD_ID")]
+    build_id: Option<String>,
+
+    /// Time for now() in templates, as seconds since the epoch or RFC 3339
+    #[clap(long, env = "SOURCE_DATE_EPOCH", value_parser = time::parse_timestamp)]
+    timestamp: Option<i64>,
+
+    /// How to treat undefined variables in templates [default: semi-strict]
+    #[clap(long, value_enum)]
+    strictness: Option<template::Strictness>,
+
+    /// Fail on any use of an undefined variable, same as --strictness strict
+    #[clap(long, conflicts_with_all = ["strictness", "lenient"])]
+    strict: bool,
+
+    /// Render undefined variables as empty, same as --strictness lenient
+    #[clap(long, conflicts_with = "strictness")]
+    lenient: bool,
 
     /// Additional docker arguments (multiple)
     #[clap(long)]
     docker_args: Vec<String>,
 
     /// Build arguments (multiple)
-    #[clap(long, short('b'))]
+    #[clap(long, short('b'), add = ArgValueCompleter::new(complete::build_args))]
     build_arg: Vec<String>,
 
     /// Push resulting docker image
-    #[clap(long)]
+    #[clap(long, overrides_with = "no_push")]
     push: bool,
 
+    /// Don't push, even if the config file says to
+    #[clap(long, overrides_with = "push")]
+    no_push: bool,
+
     /// Tag resulting docker image (multiple)
     #[clap(long, short('t'))]
     tag: Vec<String>,
 
     /// Targets to build (multiple)
-    #[clap(long)]
+    #[clap(long, add = ArgValueCompleter::new(complete::targets))]
     target: Vec<String>,
 
     /// Output path to export contents of final target
     #[clap(long)]
     output: Option<PathBuf>,
 
+    /// Output format
+    #[clap(long, value_enum, global = true, default_value_t)]
+    format: OutputFormat,
+
     #[clap(subcommand)]
     command: Option<BernCommand>,
+
+    /// Whether the profile came from BERN_PROFILE rather than --profile
+    #[clap(skip)]
+    profile_from_env: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    #[default]
+    Human,
+    Json,
 }
 
 #[derive(Clone, Debug, Subcommand)]
 enum BernCommand {
     /// Print out resulting Dockerfile
-    ShowDockerfile,
+    ShowDockerfile {
+        /// Print it again whenever the template or context changes
+        #[clap(long)]
+        watch: bool,
+
+        /// Show the template file and line each line came from
+        #[clap(long)]
+        annotate: bool,
+    },
+    /// Build, and then rebuild whenever the template or context changes
+    Watch,
     /// Export context as a tar without building
     ExportContext {
         destination: PathBuf,
+    },
+    /// List the stages in the resulting Dockerfile
+    Targets,
+    /// Print the dependency graph between stages, highlighting the selected targets
+    Graph {
+        /// Graph syntax
+        #[clap(long, value_enum, default_value_t)]
+        syntax: graph::GraphSyntax,
+    },
+    /// Check the resulting Dockerfile for common problems
+    Lint {
+        /// List the available rules instead
+        #[clap(long)]
+        list_rules: bool,
+    },
+    /// Compare the resulting Dockerfile against one rendered with other values
+    Diff {
+        /// Build arguments to use for the old side, or just a name to unset one (multiple)
+        #[clap(long)]
+        old_build_arg: Vec<String>,
+
+        /// Build profile to use for the old side, or "" for none [default: --profile]
+        #[clap(long)]
+        old_profile: Option<String>,
+
+        /// Render the template as of this git revision for the old side
+        #[clap(long)]
+        rev: Option<String>,
+    },
+    /// Print a script to enable shell completions, e.g. `source <(bern completions bash)`
+    Completions {
+        shell: complete::CompletionShell,
+    },
+    /// Print a man page
+    Man,
+    /// Write a starter template for the project in the current directory
+    Init {
+        /// Kind of project [default: detected from the current directory]
+        #[clap(long, value_enum)]
+        kind: Option<init::ProjectKind>,
+
+        /// Overwrite existing files
+        #[clap(long)]
+        force: bool,
+    },
+}
+
+impl Cli {
+    fn from_matches(matches: &ArgMatches) -> Result<Self, clap::Error> {
+        let mut cli = Self::from_arg_matches(matches)?;
+        cli.profile_from_env = matches.value_source("profile") == Some(ValueSource::EnvVariable);
+        Ok(cli)
+    }
+
+    fn settings(&self) -> config::Settings {
+        fn non_empty<T: Clone>(v: &[T]) -> Option<Vec<T>> {
+            (!v.is_empty()).then(|| v.to_vec())
+        }
+
+        config::Settings {
+            file: self.file.clone(),
+            context: self.context.clone(),
+            template_path: non_empty(&self.template_path),
+            vars_files: non_empty(&self.vars_file),
+            allow_env: non_empty(&self.allow_env),
+            build_id: self.build_id.clone(),
+            timestamp: self.timestamp,
+            strictness: self.strictness
+                .or(self.strict.then_some(template::Strictness::Strict))
+                .or(self.lenient.then_some(template::Strictness::Lenient)),
+            docker_args: non_empty(&self.docker_args),
+            build_args: (!self.build_arg.is_empty()).then(|| parse_build_args(&self.build_arg)),
+            tags: non_empty(&self.tag),
+            targets: non_empty(&self.target),
+            output: self.output.clone(),
+            push: self.push.then_some(true).or(self.no_push.then_some(false)),
+            lint: None,
+        }
     }
 }
 
+fn parse_build_args(args: &[String]) -> HashMap<String, String> {
+    args.iter()
+        .map(|a| {
+            match a.split_once('=') {
+                Some((k, v)) => (k.to_owned(), v.to_owned()),
+                None => (a.to_owned(), String::new())
+            }
+        })
+        .collect()
+}
+
 fn transform_docker_args(args: Vec<String>) -> Vec<String> {
     args.iter().flat_map(|a| shlex::split(a).unwrap_or_default()).collect()
 }
 
+/// Settings from the config file with `profile` applied, and then the command line.
+///
+/// Without a config file, a profile is only an error if it was `explicit`, rather than
+/// left over in the environment.
+fn load_settings(args: &Cli, profile: Option<&str>, explicit: bool) -> anyhow::Result<config::Settings> {
+    let template = args.file.as_deref().unwrap_or(Path::new(DEFAULT_FILE));
+    let mut settings = match config::ConfigFile::discover(args.config.as_deref(), template)? {
+        Some(config) => config.settings(profile)?,
+        None if profile.is_some() && explicit => anyhow::bail!("Profile requested, but no config file was found"),
+        None => config::Settings::default(),
+    };
+
+    settings.merge(args.settings());
+    settings.validate()?;
+
+    Ok(settings)
+}
+
+fn bern_config(settings: config::Settings, stage_dir: &Path) -> build::BernConfig {
+    build::BernConfig {
+        stage_dir: stage_dir.to_owned(),
+        file: settings.file.unwrap_or_else(|| PathBuf::from(DEFAULT_FILE)),
+        context_root: settings.context.unwrap_or_else(|| PathBuf::from(".")),
+        template_path: settings.template_path.unwrap_or_default().into_iter()
+            .chain(config::user_template_dir())
+            .collect(),
+        vars_files: settings.vars_files.unwrap_or_default(),
+        allow_env: settings.allow_env.unwrap_or_default(),
+        build_id: settings.build_id,
+        timestamp: settings.timestamp,
+        strictness: settings.strictness.unwrap_or_default(),
+        docker_args: transform_docker_args(settings.docker_args.unwrap_or_default()),
+        docker_tags: settings.tags.unwrap_or_default(),
+        build_args: settings.build_args.unwrap_or_default(),
+        targets: settings.targets.unwrap_or_default(),
+        output: settings.output,
+    }
+}
+
+fn show_dockerfile(build: &build::BernBuild, annotate: bool) -> anyhow::Result<()> {
+    if annotate {
+        let mut dockerfile = Vec::new();
+        build.render_to(&mut dockerfile)?;
+        io::stdout().write_all(build.source_map().annotate(&String::from_utf8_lossy(&dockerfile)).as_bytes())?;
+    } else {
+        build.render_to(std::io::stdout())?;
+    }
+    Ok(())
+}
+
 fn main() -> anyhow::Result<()> {
-    let args = Cli::parse();
+    complete::complete();
+    let args = Cli::from_matches(&Cli::command().get_matches()).unwrap_or_else(|e| e.exit());
+
+    match args.command {
+        Some(BernCommand::Completions { shell }) => return Ok(complete::write_registration(shell, &mut io::stdout())?),
+        Some(BernCommand::Man) => return Ok(complete::write_man(&mut io::stdout())?),
+        _ => {},
+    }
+
+    let supports_json = !matches!(args.command,
+        Some(BernCommand::ShowDockerfile { watch: true, .. } | BernCommand::Watch | BernCommand::Graph { .. } | BernCommand::Lint { list_rules: true }
+            | BernCommand::ExportContext { .. } | BernCommand::Init { .. }));
+    if args.format == OutputFormat::Json && !supports_json {
+        anyhow::bail!("--format json is not supported by this command");
+    }
+
+    // Starting a project shouldn't depend on its config
+    if let Some(BernCommand::Init { kind, force }) = args.command {
+        let file = args.file.as_deref().unwrap_or(Path::new(DEFAULT_FILE));
+        let context = args.context.as_deref().unwrap_or(Path::new("."));
+        return init::init(file, context, kind, force);
+    }
+
+    let mut settings = load_settings(&args, args.profile.as_deref(), !args.profile_from_env)?;
 
     let stage_dir = tempfile::tempdir()?;
-    let build_args = args.build_arg.into_iter()
-        .map(|a| {
-            match a.split_once('=') {
-                Some((k, v)) => (k.to_owned(), v.to_owned()),
-                None => (a, String::new())
-            }
-        
-        })
-        .collect();
-    let build = build::BernBuild::new(build::BernConfig {
-        stage_dir: stage_dir.path().to_owned(),
-        file: args.file,
-        context_root: PathBuf::from("."),
-        docker_args: transform_docker_args(args.docker_args),
-        docker_tags: args.tag,
-        build_args,
-        targets: args.target,
-        output: args.output,
-    });
+    let push = settings.push.unwrap_or_default();
+    let lint_settings = settings.lint.take().unwrap_or_default();
+    let build_config = bern_config(settings, stage_dir.path());
+    let build = build::BernBuild::new(build_config.clone());
 
     match args.command {
-        Some(BernCommand::ShowDockerfile) => {
-            build.render_to(std::io::stdout())?;
+        Some(BernCommand::ShowDockerfile { watch: false, annotate }) if args.format == OutputFormat::Json => {
+            let mut dockerfile = Vec::new();
+            build.render_to(&mut dockerfile)?;
+            let mut json = serde_json::json!({
+                "dockerfile": String::from_utf8_lossy(&dockerfile),
+                "stages": build.stages(),
+            });
+            if annotate {
+                json["source_map"] = serde_json::to_value(&*build.source_map())?;
+            }
+            println!("{}", serde_json::to_string_pretty(&json)?);
+            Ok(())
+        },
+        Some(BernCommand::ShowDockerfile { watch: false, annotate }) => {
+            show_dockerfile(&build, annotate)
+        },
+        Some(BernCommand::ShowDockerfile { watch: true, annotate }) => {
+            watch::watch(&build_config, |build| {
+                show_dockerfile(build, annotate)?;
+                Ok(std::io::stdout().flush()?)
+            })
+        },
+        Some(BernCommand::Watch) => {
+            watch::watch(&build_config, |build| build.build(&mut build::Report::default()))
+        },
+        Some(BernCommand::Targets) => {
+            build.render_to(io::sink())?;
+            let stages = build.stages();
+
+            match args.format {
+                OutputFormat::Human => {
+                    let width = stages.iter().filter_map(|s| s.name.as_ref()).map(|n| n.len()).max().unwrap_or(1);
+                    for stage in &stages {
+                        let default = if stage.default { " (default)" } else { "" };
+                        println!("{:>3}  {:<width$}  FROM {}{default}", stage.index, stage.name.as_deref().unwrap_or("-"), stage.src);
+                    }
+                },
+                OutputFormat::Json => {
+                    println!("{}", serde_json::to_string_pretty(&stages)?);
+                },
+            }
+
+            Ok(())
+        },
+        Some(BernCommand::Graph { syntax }) => {
+            let instructions = build.render_instructions(io::sink())?;
+            let graph = graph::StageGraph::from_instructions(&instructions);
+
+            let output = match syntax {
+                graph::GraphSyntax::Dot => graph.to_dot(&build_config.targets)?,
+                graph::GraphSyntax::Mermaid => graph.to_mermaid(&build_config.targets)?,
+            };
+            print!("{output}");
+
             Ok(())
         },
+        Some(BernCommand::Lint { list_rules: true }) => {
+            for rule in lint::RULES {
+                let default = if rule.default { "" } else { " (off by default)" };
+                println!("{:<20} {:<8} {}{default}", rule.name, rule.severity, rule.description);
+            }
+            Ok(())
+        },
+        Some(BernCommand::Lint { list_rules: false }) => {
+            let instructions = build.render_instructions(io::sink())?;
+            let findings = lint::lint(&instructions, &lint_settings)?;
+            let errors = findings.iter().filter(|f| f.rule.severity == lint::Severity::Error).count();
+
+            match args.format {
+                OutputFormat::Human => {
+                    for finding in &findings {
+                        println!("Dockerfile:{}: {finding}", finding.line);
+                    }
+                },
+                OutputFormat::Json => {
+                    let findings: Vec<_> = findings.iter().map(|f| serde_json::json!({
+                        "rule": f.rule.name,
+                        "severity": f.rule.severity.to_string(),
+                        "line": f.line,
+                        "message": f.message,
+                    })).collect();
+                    println!("{}", serde_json::to_string_pretty(&findings)?);
+                },
+            }
+
+            if errors > 0 {
+                anyhow::bail!("Lint found {errors} error(s)");
+            }
+
+            Ok(())
+        },
+        Some(BernCommand::Diff { ref old_build_arg, ref old_profile, ref rev }) => {
+            let mut old_settings = match old_profile.as_deref() {
+                Some("") => load_settings(&args, None, false)?,
+                Some(profile) => load_settings(&args, Some(profile), true)?,
+                None => load_settings(&args, args.profile.as_deref(), !args.profile_from_env)?,
+            };
+            // A name without a value unsets the build argument
+            let (set, unset): (Vec<_>, Vec<_>) = old_build_arg.iter().cloned().partition(|a| a.contains('='));
+            old_settings.merge(config::Settings {
+                build_args: Some(parse_build_args(&set)),
+                ..Default::default()
+            });
+
+            let old_dir = tempfile::tempdir()?;
+            let mut old_config = bern_config(old_settings, old_dir.path());
+            for name in &unset {
+                old_config.build_args.remove(name);
+            }
+            let new_label = build_config.file.display().to_string();
+            let old_label = match rev {
+                Some(rev) => {
+                    // Included templates still come from the working tree
+                    let contents = diff::git_show(rev, &old_config.file)?;
+                    let path = old_dir.path().join(old_config.file.file_name().unwrap_or("Dockerfile.j2".as_ref()));
+                    fs::write(&path, contents)?;
+                    old_config.file = path;
+                    format!("{new_label}@{rev}")
+                },
+                None => format!("{new_label} (old)"),
+            };
+
+            let old = diff::Rendered::render(&build::BernBuild::new(old_config))?;
+            let new = diff::Rendered::render(&build)?;
+            let diff = diff::diff(&old, &new, &old_label, &new_label);
+
+            match args.format {
+                OutputFormat::Human => print!("{diff}"),
+                OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&diff)?),
+            }
+
+            Ok(())
+        },
+        Some(BernCommand::Completions { .. } | BernCommand::Man | BernCommand::Init { .. }) => unreachable!("handled before loading settings"),
         Some(BernCommand::ExportContext { destination }) => {
             let output: Box<dyn io::Write> = if destination.as_os_str() == "-" {
                 Box::new(std::io::stdout())
@@ -96,13 +467,20 @@ fn main() -> anyhow::Result<()> {
             Ok(())
         },
         None => {
-            build.build()?;
+            let mut report = build::Report {
+                quiet: args.format == OutputFormat::Json,
+                ..Default::default()
+            };
+
+            let result = build.build(&mut report)
+                .and_then(|()| if push { build.push(&mut report) } else { Ok(()) });
 
-            if args.push {
-                build.push()?;
+            if args.format == OutputFormat::Json {
+                report.error = result.as_ref().err().map(|e| format!("{e:#}"));
+                println!("{}", serde_json::to_string_pretty(&report)?);
             }
 
-            Ok(())
+            result
         },
     }
 }
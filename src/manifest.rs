@@ -0,0 +1,295 @@
+use std::{fs, path::{Path, PathBuf}};
+
+use anyhow::Context as _;
+use serde::Serialize;
+use serde_json::Value as Json;
+
+use minijinja::Value;
+
+use crate::{context::{self, DockerIgnore}, template::IntoValue};
+
+/// What templates usually want to know about a project, from its manifest.
+#[derive(Debug, Default, Serialize)]
+pub struct Manifest {
+    pub name: Option<String>,
+    pub version: Option<String>,
+    /// Paths of workspace members, relative to the context
+    pub workspace_members: Vec<String>,
+    pub binaries: Vec<String>,
+    /// The whole parsed file
+    pub data: Json,
+}
+
+impl IntoValue for Manifest {
+    fn into_value(self) -> Result<Value, minijinja::Error> {
+        Ok(Value::from_serialize(self))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Kind {
+    Cargo,
+    Npm,
+    Python,
+    Go,
+}
+
+impl Kind {
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            Self::Cargo => "Cargo.toml",
+            Self::Npm => "package.json",
+            Self::Python => "pyproject.toml",
+            Self::Go => "go.mod",
+        }
+    }
+}
+
+/// Read a manifest, at `path` relative to the context root.
+pub fn read(kind: Kind, root: &Path, path: &Path) -> anyhow::Result<Manifest> {
+    let contents = fs::read_to_string(root.join(path))
+        .with_context(|| format!("Failed to read file: {}", path.display()))?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+
+    let manifest = match kind {
+        Kind::Cargo => cargo(root, dir, toml::from_str(&contents)?),
+        Kind::Npm => npm(root, dir, serde_json::from_str(&contents)?),
+        Kind::Python => python(root, dir, toml::from_str(&contents)?),
+        Kind::Go => go(root, dir, &contents),
+    };
+    manifest.with_context(|| format!("Failed to parse file: {}", path.display()))
+}
+
+fn string(value: &Json, pointer: &str) -> Option<String> {
+    value.pointer(pointer)?.as_str().map(str::to_owned)
+}
+
+fn strings(value: Option<&Json>) -> Vec<String> {
+    value.and_then(Json::as_array).into_iter().flatten()
+        .filter_map(|v| v.as_str().map(str::to_owned))
+        .collect()
+}
+
+/// Expand workspace member patterns to the directories that have the given manifest.
+fn members(root: &Path, dir: &Path, patterns: &[String], manifest: &str) -> anyhow::Result<Vec<String>> {
+    let ignore = DockerIgnore::read(root);
+    let mut members = Vec::new();
+    for pattern in patterns {
+        let pattern = dir.join(pattern.trim_end_matches('/')).join(manifest);
+        for path in context::glob(root, &ignore, &pattern.to_string_lossy())? {
+            let member = path.parent().unwrap_or(Path::new("")).to_string_lossy().into_owned();
+            if !members.contains(&member) {
+                members.push(member);
+            }
+        }
+    }
+    Ok(members)
+}
+
+fn cargo(root: &Path, dir: &Path, data: Json) -> anyhow::Result<Manifest> {
+    let name = string(&data, "/package/name");
+    // Inherited with `version.workspace = true`
+    let version = string(&data, "/package/version").or_else(|| string(&data, "/workspace/package/version"));
+
+    let mut binaries: Vec<_> = data.get("bin").and_then(Json::as_array).into_iter().flatten()
+        .filter_map(|b| string(b, "/name"))
+        .collect();
+    if let Some(name) = &name && root.join(dir).join("src/main.rs").is_file() && !binaries.contains(name) {
+        binaries.insert(0, name.clone());
+    }
+    if let Ok(entries) = fs::read_dir(root.join(dir).join("src/bin")) {
+        let mut extra: Vec<_> = entries.filter_map(Result::ok)
+            .filter_map(|e| {
+                let path = PathBuf::from(e.file_name());
+                (path.extension()? == "rs").then(|| path.file_stem().unwrap().to_string_lossy().into_owned())
+            })
+            .filter(|b| !binaries.contains(b))
+            .collect();
+        extra.sort();
+        binaries.extend(extra);
+    }
+
+    Ok(Manifest {
+        name,
+        version,
+        workspace_members: members(root, dir, &strings(data.pointer("/workspace/members")), "Cargo.toml")?,
+        binaries,
+        data,
+    })
+}
+
+fn npm(root: &Path, dir: &Path, data: Json) -> anyhow::Result<Manifest> {
+    let name = string(&data, "/name");
+    let binaries = match data.get("bin") {
+        Some(Json::String(_)) => name.iter().map(|n| n.rsplit('/').next().unwrap_or(n).to_owned()).collect(),
+        Some(Json::Object(bins)) => bins.keys().cloned().collect(),
+        _ => Vec::new(),
+    };
+    let workspaces = match data.get("workspaces") {
+        Some(Json::Object(w)) => strings(w.get("packages")),
+        w => strings(w),
+    };
+
+    Ok(Manifest {
+        name,
+        version: string(&data, "/version"),
+        workspace_members: members(root, dir, &workspaces, "package.json")?,
+        binaries,
+        data,
+    })
+}
+
+fn python(root: &Path, dir: &Path, data: Json) -> anyhow::Result<Manifest> {
+    let scripts = data.pointer("/project/scripts").or_else(|| data.pointer("/tool/poetry/scripts"));
+
+    Ok(Manifest {
+        name: string(&data, "/project/name").or_else(|| string(&data, "/tool/poetry/name")),
+        version: string(&data, "/project/version").or_else(|| string(&data, "/tool/poetry/version")),
+        workspace_members: members(root, dir, &strings(data.pointer("/tool/uv/workspace/members")), "pyproject.toml")?,
+        binaries: scripts.and_then(Json::as_object).map(|s| s.keys().cloned().collect()).unwrap_or_default(),
+        data,
+    })
+}
+
+/// Directives of a go.mod or go.work file, with their arguments, blocks flattened.
+fn go_directives(contents: &str) -> Vec<(String, String)> {
+    let mut directives = Vec::new();
+    let mut block = None;
+    for line in contents.lines() {
+        let line = line.split("//").next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(name) = &block {
+            if line == ")" {
+                block = None;
+            } else {
+                directives.push((String::clone(name), line.to_owned()));
+            }
+        } else if let Some((name, args)) = line.split_once(char::is_whitespace) {
+            match args.trim() {
+                "(" => block = Some(name.to_owned()),
+                args => directives.push((name.to_owned(), args.to_owned())),
+            }
+        }
+    }
+    directives
+}
+
+fn go(root: &Path, dir: &Path, contents: &str) -> anyhow::Result<Manifest> {
+    let directives = go_directives(contents);
+    let find = |name: &str| directives.iter().find(|d| d.0 == name).map(|d| d.1.trim_matches('"').to_owned());
+    let module = find("module").context("No module directive")?;
+
+    let mut workspace_members = Vec::new();
+    if let Ok(work) = fs::read_to_string(root.join(dir).join("go.work")) {
+        for (name, path) in go_directives(&work) {
+            if name == "use" {
+                let path = dir.join(path.trim_matches('"'));
+                workspace_members.push(path.to_string_lossy().trim_start_matches("./").to_owned());
+            }
+        }
+    }
+
+    // Main packages are usually the module itself, or directories under cmd/
+    let mut binaries = Vec::new();
+    if root.join(dir).join("main.go").is_file() {
+        binaries.push(module.rsplit('/').next().unwrap_or(&module).to_owned());
+    }
+    if let Ok(entries) = fs::read_dir(root.join(dir).join("cmd")) {
+        let mut cmds: Vec<_> = entries.filter_map(Result::ok)
+            .filter(|e| e.path().is_dir())
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .collect();
+        cmds.sort();
+        binaries.extend(cmds);
+    }
+
+    let data = serde_json::json!({
+        "module": module,
+        "go": find("go"),
+        "toolchain": find("toolchain"),
+    });
+
+    Ok(Manifest {
+        name: Some(module),
+        version: None,
+        workspace_members,
+        binaries,
+        data,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn project(files: &[(&str, &str)]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for (path, contents) in files {
+            let path = dir.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_cargo() {
+        let dir = project(&[
+            ("Cargo.toml", "[workspace]\nmembers = [\"crates/*\"]\n[workspace.package]\nversion = \"1.2.0\"\n[package]\nname = \"app\"\nversion.workspace = true\n[[bin]]\nname = \"tool\"\npath = \"src/tool.rs\"\n"),
+            ("src/main.rs", ""),
+            ("src/bin/extra.rs", ""),
+            ("crates/a/Cargo.toml", "[package]\nname = \"a\"\n"),
+            ("crates/notes/README.md", ""),
+        ]);
+
+        let manifest = read(Kind::Cargo, dir.path(), Path::new("Cargo.toml")).unwrap();
+        assert_eq!(manifest.name.as_deref(), Some("app"));
+        assert_eq!(manifest.version.as_deref(), Some("1.2.0"));
+        assert_eq!(manifest.workspace_members, vec!["crates/a"]);
+        assert_eq!(manifest.binaries, vec!["app", "tool", "extra"]);
+    }
+
+    #[test]
+    fn test_npm() {
+        let dir = project(&[
+            ("package.json", r#"{"name": "@org/cli", "version": "0.1.0", "bin": "cli.js", "workspaces": ["packages/*"]}"#),
+            ("packages/ui/package.json", "{}"),
+        ]);
+
+        let manifest = read(Kind::Npm, dir.path(), Path::new("package.json")).unwrap();
+        assert_eq!(manifest.name.as_deref(), Some("@org/cli"));
+        assert_eq!(manifest.workspace_members, vec!["packages/ui"]);
+        assert_eq!(manifest.binaries, vec!["cli"]);
+    }
+
+    #[test]
+    fn test_python() {
+        let dir = project(&[
+            ("pyproject.toml", "[project]\nname = \"svc\"\nversion = \"2.0\"\n[project.scripts]\nsvc = \"svc:main\"\n"),
+        ]);
+
+        let manifest = read(Kind::Python, dir.path(), Path::new("pyproject.toml")).unwrap();
+        assert_eq!(manifest.name.as_deref(), Some("svc"));
+        assert_eq!(manifest.version.as_deref(), Some("2.0"));
+        assert_eq!(manifest.binaries, vec!["svc"]);
+    }
+
+    #[test]
+    fn test_go() {
+        let dir = project(&[
+            ("go.mod", "module example.com/org/server // comment\n\ngo 1.22\n\nrequire (\n\tgithub.com/a/b v1.0.0\n)\n"),
+            ("go.work", "go 1.22\n\nuse (\n\t.\n\t./tools\n)\n"),
+            ("main.go", ""),
+            ("cmd/migrate/main.go", ""),
+        ]);
+
+        let manifest = read(Kind::Go, dir.path(), Path::new("go.mod")).unwrap();
+        assert_eq!(manifest.name.as_deref(), Some("example.com/org/server"));
+        assert_eq!(manifest.data["go"], "1.22");
+        assert_eq!(manifest.workspace_members, vec![".", "tools"]);
+        assert_eq!(manifest.binaries, vec!["server", "migrate"]);
+    }
+}
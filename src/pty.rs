@@ -0,0 +1,111 @@
+use std::{collections::VecDeque, io, process::{Command, ExitStatus}};
+
+/// How much of the end of a command's stderr is kept.
+const TAIL_SIZE: usize = 64 * 1024;
+
+/// Run `command` with its stderr on a pseudo-terminal, passing everything it writes
+/// through to our stderr, and give back the end of what it wrote.
+///
+/// The command still sees a terminal, so docker keeps its interactive progress display.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd"))]
+pub fn run(command: &mut Command) -> io::Result<(ExitStatus, Vec<u8>)> {
+    use std::{fs, io::{Read as _, Write as _}, os::{fd::OwnedFd, unix::fs::OpenOptionsExt as _}, process::Stdio};
+
+    use rustix::pty::{self, OpenptFlags};
+
+    let mut master = fs::File::from(pty::openpt(OpenptFlags::RDWR | OpenptFlags::NOCTTY | OpenptFlags::CLOEXEC)?);
+    pty::grantpt(&master)?;
+    pty::unlockpt(&master)?;
+    let name = pty::ptsname(&master, Vec::new())?;
+    let slave = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(rustix::fs::OFlags::NOCTTY.bits() as i32)
+        .open(name.to_string_lossy().as_ref())?;
+    if let Ok(size) = rustix::termios::tcgetwinsize(io::stderr()) {
+        let _ = rustix::termios::tcsetwinsize(&slave, size);
+    }
+
+    let spawned = command.stderr(Stdio::from(OwnedFd::from(slave))).spawn();
+    // Let go of our copy of the terminal, so reading ends when the command exits
+    command.stderr(Stdio::inherit());
+    let mut child = spawned?;
+
+    let mut tail = VecDeque::with_capacity(TAIL_SIZE);
+    let mut buffer = [0; 4096];
+    loop {
+        let len = match master.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            // Linux reports the other end closing as an error
+            Err(e) if e.raw_os_error() == Some(rustix::io::Errno::IO.raw_os_error()) => break,
+            Err(e) => return Err(e),
+        };
+        io::stderr().write_all(&buffer[..len])?;
+
+        tail.extend(&buffer[..len]);
+        tail.drain(..tail.len().saturating_sub(TAIL_SIZE));
+    }
+
+    Ok((child.wait()?, tail.into()))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd")))]
+pub fn run(command: &mut Command) -> io::Result<(ExitStatus, Vec<u8>)> {
+    Ok((command.status()?, Vec::new()))
+}
+
+/// Lines of terminal output as they were last shown, without escape sequences.
+pub fn plain_lines(output: &[u8]) -> Vec<String> {
+    let text = String::from_utf8_lossy(output);
+    let mut lines = Vec::new();
+    for line in text.split('\n').map(|l| l.trim_end_matches('\r')) {
+        let mut plain = String::new();
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                // CSI sequences end with a letter, OSC sequences with BEL or ST
+                '\x1b' if chars.peek() == Some(&'[') => {
+                    chars.by_ref().find(|c| ('@'..='~').contains(c) && *c != '[');
+                },
+                '\x1b' if chars.peek() == Some(&']') => {
+                    while let Some(c) = chars.next() {
+                        if c == '\x07' || (c == '\x1b' && chars.next_if_eq(&'\\').is_some()) {
+                            break;
+                        }
+                    }
+                },
+                '\r' => plain.clear(),
+                c if c.is_control() && c != '\t' => {},
+                c => plain.push(c),
+            }
+        }
+        lines.push(plain);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain_lines() {
+        assert_eq!(
+            plain_lines(b"\x1b[1;31mERROR\x1b[0m: failed\r\n#1 10%\r#1 done\n\x1b]0;title\x07Dockerfile:3"),
+            vec!["ERROR: failed", "#1 done", "Dockerfile:3"],
+        );
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd"))]
+    #[test]
+    fn test_run() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "test -t 2 && echo 'Dockerfile:2' >&2; exit 3"]);
+
+        let (status, tail) = run(&mut command).unwrap();
+        assert_eq!(status.code(), Some(3));
+        assert_eq!(plain_lines(&tail), vec!["Dockerfile:2", ""]);
+    }
+}
@@ -0,0 +1,436 @@
+use std::{borrow::Cow, fmt::Write as _};
+
+use serde::Serialize;
+
+/// Where a line of the rendered Dockerfile came from in the templates.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Origin {
+    pub file: String,
+    pub line: usize,
+}
+
+/// Template origins of each line of a rendered Dockerfile.
+///
+/// minijinja doesn't say which template line produced which output, so templates are loaded
+/// with a marker in front of each line (see [`mark`]), which is taken out of the output
+/// again as it is written (see [`Recorder`]).  A line of output comes from the template line
+/// whose marker was the last one before its first character.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct SourceMap {
+    /// Origin of each rendered line, starting from line 1
+    lines: Vec<Option<Origin>>,
+}
+
+// Unicode noncharacters are meant for internal use, so they won't be in a real template
+const MARK_START: char = '\u{FDD0}';
+const MARK_END: char = '\u{FDD1}';
+
+/// A `{{ }}`, `{% %}` or `{# #}` tag in a template, with whether it trims the whitespace
+/// in front of it, as in `{%- ... %}`.
+#[derive(Debug)]
+struct Tag {
+    start: usize,
+    end: usize,
+    trim_before: bool,
+}
+
+/// The tags of a template, with the contents of `{% raw %}` blocks left out.
+fn tags(source: &str) -> Vec<Tag> {
+    let mut tags = Vec::new();
+    let mut pos = 0;
+    let mut in_raw = false;
+    while let Some(offset) = source[pos..].find('{') {
+        let start = pos + offset;
+        let close = match source.as_bytes().get(start + 1) {
+            Some(b'%') => "%}",
+            Some(b'{') if !in_raw => "}}",
+            Some(b'#') if !in_raw => "#}",
+            _ => {
+                pos = start + 1;
+                continue;
+            },
+        };
+        let end = tag_end(source, start + 2, close);
+        let inner = &source[start + 2..end.saturating_sub(2).max(start + 2)];
+        let name = inner.trim_matches(|c: char| c == '-' || c == '+' || c.is_whitespace());
+        pos = end;
+
+        // Everything up to {% endraw %} is output as it is
+        if in_raw && name != "endraw" {
+            continue;
+        }
+        in_raw = close == "%}" && name == "raw";
+
+        tags.push(Tag {
+            start,
+            end,
+            trim_before: inner.starts_with('-'),
+        });
+    }
+    tags
+}
+
+/// The end of a tag whose contents start at `pos`, skipping over strings in expressions.
+fn tag_end(source: &str, pos: usize, close: &str) -> usize {
+    let mut chars = source[pos..].char_indices();
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            '"' | '\'' if close != "#}" => {
+                while let Some((_, s)) = chars.next() {
+                    match s {
+                        '\\' => { chars.next(); },
+                        s if s == c => break,
+                        _ => {},
+                    }
+                }
+            },
+            _ if source[pos + idx..].starts_with(close) => return pos + idx + close.len(),
+            _ => {},
+        }
+    }
+    source.len()
+}
+
+/// Put a marker naming the template and line in front of each line of `source` that can
+/// produce output.
+///
+/// The markers go where they can't change how whitespace is trimmed around tags: in front
+/// of the first character of the line, or for a line starting with a tag like `{{-`, which
+/// trims the whitespace in front of it, right after the last text before that whitespace.
+/// Lines that start inside a tag have none, so their output counts as the tag's.  Text
+/// captured by a `{% set %}` block keeps its markers until it is written out.
+pub fn mark(name: &str, source: &str) -> String {
+    let tags = tags(source);
+    let mut marks = Vec::new();
+
+    let mut line_start = 0;
+    for (idx, line) in source.split_inclusive('\n').enumerate() {
+        let start = line_start;
+        line_start += line.len();
+        if tags.iter().any(|t| t.start < start && start < t.end) {
+            continue;
+        }
+
+        let first = start + (line.len() - line.trim_start_matches([' ', '\t']).len());
+        if source[first..].starts_with(['\r', '\n']) || first == source.len() {
+            continue;
+        }
+
+        let position = match tags.iter().position(|t| t.start == first) {
+            Some(tag) if tags[tag].trim_before => {
+                let text_start = tag.checked_sub(1).map_or(0, |t| tags[t].end);
+                text_start + source[text_start..first].trim_end().len()
+            },
+            _ => first,
+        };
+        marks.push((position, idx + 1));
+    }
+    marks.sort();
+
+    let mut marked = String::with_capacity(source.len() + marks.len() * (name.len() + 8));
+    let mut pos = 0;
+    for (position, line) in marks {
+        marked.push_str(&source[pos..position]);
+        let _ = write!(marked, "{MARK_START}{name}:{line}{MARK_END}");
+        pos = position;
+    }
+    marked.push_str(&source[pos..]);
+    marked
+}
+
+/// `text` without the markers [`mark`] put in it.
+pub fn unmark(text: &str) -> Cow<'_, str> {
+    if !text.contains(MARK_START) {
+        return Cow::Borrowed(text);
+    }
+
+    let mut unmarked = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(MARK_START) {
+        unmarked.push_str(&rest[..start]);
+        rest = rest[start..].find(MARK_END).map_or("", |end| &rest[start + end + MARK_END.len_utf8()..]);
+    }
+    unmarked.push_str(rest);
+    Cow::Owned(unmarked)
+}
+
+/// Where a byte offset into marked text ends up once the markers are taken out.
+pub fn unmarked_offset(text: &str, offset: usize) -> usize {
+    unmark(&text[..offset.min(text.len())]).len()
+}
+
+/// Takes the markers out of rendered output as it is written, and notes where each line
+/// of the output came from.
+#[derive(Debug, Default)]
+pub struct Recorder {
+    /// A marker cut off at the end of the last write
+    pending: Vec<u8>,
+    /// The template line being rendered, from the last marker
+    current: Option<Origin>,
+    /// Origin of the output line being written, once it has something other than whitespace
+    line: Option<Option<Origin>>,
+    /// Whether anything was written on the current output line
+    started: bool,
+    lines: Vec<Option<Origin>>,
+}
+
+impl Recorder {
+    /// Add `output` to what was rendered, writing it to `text` without the markers.
+    pub fn write(&mut self, output: &[u8], text: &mut Vec<u8>) {
+        let start = MARK_START.to_string();
+        let end = MARK_END.to_string();
+
+        let mut data = std::mem::take(&mut self.pending);
+        data.extend_from_slice(output);
+
+        let mut rest = &data[..];
+        while let Some(idx) = find(rest, start.as_bytes()) {
+            self.text(&rest[..idx], text);
+            let marker = &rest[idx + start.len()..];
+            let Some(len) = find(marker, end.as_bytes()) else {
+                self.pending = rest[idx..].to_vec();
+                return;
+            };
+            self.current = std::str::from_utf8(&marker[..len]).ok()
+                .and_then(|m| m.rsplit_once(':'))
+                .and_then(|(file, line)| Some(Origin { file: file.to_owned(), line: line.parse().ok()? }));
+            rest = &marker[len + end.len()..];
+        }
+
+        // The start of a marker may be cut off at the end
+        let keep = (1..start.len()).rev().find(|&n| rest.ends_with(&start.as_bytes()[..n])).unwrap_or(0);
+        self.text(&rest[..rest.len() - keep], text);
+        self.pending = rest[rest.len() - keep..].to_vec();
+    }
+
+    fn text(&mut self, output: &[u8], text: &mut Vec<u8>) {
+        for &byte in output {
+            if byte == b'\n' {
+                self.lines.push(self.line.take().flatten());
+                self.started = false;
+                continue;
+            }
+            self.started = true;
+            if self.line.is_none() && !byte.is_ascii_whitespace() {
+                self.line = Some(self.current.clone());
+            }
+        }
+        text.extend_from_slice(output);
+    }
+
+    /// The source map of everything written, writing anything held back to `text`.
+    pub fn finish(&mut self, text: &mut Vec<u8>) -> SourceMap {
+        let pending = std::mem::take(&mut self.pending);
+        self.text(&pending, text);
+        if self.started {
+            self.lines.push(self.line.take().flatten());
+        }
+        SourceMap { lines: std::mem::take(&mut self.lines) }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+impl SourceMap {
+    /// Where a (1-based) line of the rendered Dockerfile came from.
+    pub fn origin(&self, line: usize) -> Option<&Origin> {
+        self.lines.get(line.checked_sub(1)?)?.as_ref()
+    }
+
+    /// The rendered Dockerfile with the origin of each line in front of it.
+    pub fn annotate(&self, rendered: &str) -> String {
+        let labels: Vec<_> = (1..=rendered.lines().count())
+            .map(|line| self.origin(line).map(|o| format!("{}:{}", o.file, o.line)).unwrap_or_default())
+            .collect();
+        let width = labels.iter().map(|l| l.len()).max().unwrap_or(0);
+
+        let mut annotated = String::new();
+        for (label, line) in labels.iter().zip(rendered.lines()) {
+            let _ = writeln!(annotated, "{}", format!("{label:<width$} | {line}").trim_end());
+        }
+        annotated
+    }
+
+    /// Point docker's errors about lines of the rendered Dockerfile at the templates.
+    ///
+    /// Only docker's own messages are changed: the `Dockerfile:12` heading of the snippet
+    /// shown with a failed step becomes `Dockerfile:12 (Dockerfile.j2:7)`, and likewise
+    /// `dockerfile parse error on line 12`.  Output of the build steps is left alone.
+    pub fn annotate_docker_output(&self, text: &str) -> String {
+        let mut annotated = String::with_capacity(text.len());
+        for line in text.split_inclusive('\n') {
+            let content = line.trim_end();
+            let number = match content.trim_start().strip_prefix("Dockerfile:") {
+                Some(number) if !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()) => Some(content.len()),
+                _ => ["dockerfile parse error on line ", "dockerfile parse error line "].iter()
+                    .find_map(|m| Some(line.find(m)? + m.len()))
+                    .map(|start| start + line[start..].find(|c: char| !c.is_ascii_digit()).unwrap_or(line.len() - start)),
+            };
+
+            let origin = number.and_then(|end| {
+                let start = line[..end].rfind(|c: char| !c.is_ascii_digit()).map_or(0, |i| i + 1);
+                self.origin(line[start..end].parse().ok()?).map(|o| (end, o))
+            });
+            match origin {
+                Some((end, origin)) => {
+                    let _ = write!(annotated, "{} ({}:{}){}", &line[..end], origin.file, origin.line, &line[end..]);
+                },
+                None => annotated.push_str(line),
+            }
+        }
+        annotated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Render the first of `templates` like bern does, giving the output and its source map.
+    fn render(templates: &[(&'static str, &'static str)]) -> (String, SourceMap) {
+        let mut env = minijinja::Environment::new();
+        for (name, source) in templates {
+            env.add_template_owned(*name, mark(name, source)).unwrap();
+        }
+        let output = env.get_template(templates[0].0).unwrap().render(()).unwrap();
+
+        let mut recorder = Recorder::default();
+        let mut text = Vec::new();
+        recorder.write(output.as_bytes(), &mut text);
+        let map = recorder.finish(&mut text);
+        (String::from_utf8(text).unwrap(), map)
+    }
+
+    /// Render the first of `templates` without markers.
+    fn render_plain(templates: &[(&'static str, &'static str)]) -> String {
+        let mut env = minijinja::Environment::new();
+        for (name, source) in templates {
+            env.add_template(name, source).unwrap();
+        }
+        env.get_template(templates[0].0).unwrap().render(()).unwrap()
+    }
+
+    fn origins(map: &SourceMap, lines: usize) -> Vec<Option<String>> {
+        (1..=lines).map(|line| map.origin(line).map(|o| format!("{}:{}", o.file, o.line))).collect()
+    }
+
+    const TEMPLATES: &[(&str, &str)] = &[
+        ("Dockerfile.j2", "\
+{% import \"macros.j2\" as m %}
+FROM alpine:3.20 AS base
+{% for pkg in [\"curl\", \"git\"] %}
+RUN apk add {{ pkg }}
+{% endfor %}
+{{ m.user() }}
+"),
+        ("macros.j2", "{% macro user() -%}\nUSER {{ 1000 }}:{{ 1000 }}\n{%- endmacro %}\n"),
+    ];
+
+    #[test]
+    fn test_map() {
+        let (output, map) = render(TEMPLATES);
+        assert_eq!(output, render_plain(TEMPLATES));
+        assert_eq!(output, "\nFROM alpine:3.20 AS base\n\nRUN apk add curl\n\nRUN apk add git\n\nUSER 1000:1000");
+        assert_eq!(origins(&map, 8), vec![
+            None,
+            Some("Dockerfile.j2:2".into()),
+            None,
+            Some("Dockerfile.j2:4".into()),
+            None,
+            Some("Dockerfile.j2:4".into()),
+            None,
+            Some("macros.j2:2".into()),
+        ]);
+    }
+
+    #[test]
+    fn test_same_text() {
+        // Lines that look alike are told apart
+        let templates = &[("t", "FROM a AS one\nRUN apt-get update\nFROM b AS two\nRUN apt-get update\n")];
+        let (_, map) = render(templates);
+        assert_eq!(origins(&map, 4), vec![Some("t:1".into()), Some("t:2".into()), Some("t:3".into()), Some("t:4".into())]);
+    }
+
+    #[test]
+    fn test_whitespace_control() {
+        let templates = &[("t", "\
+FROM a
+  {{- ' AS b' }}
+RUN c \\
+  {%- for p in ['d', 'e'] %}
+    {{ p }}
+  {%- endfor %}
+{# a
+comment #}
+{% raw %}  {{- raw }}
+  {% endraw %}
+{{ 'f\\ng' }} {{ \"}}\" }}
+{% if true -%}
+   RUN h
+{%- endif %}
+")];
+        let (output, map) = render(templates);
+        assert_eq!(output, render_plain(templates));
+        assert_eq!(output, "FROM a AS b\nRUN c \\\n    d\n    e\n\n  {{- raw }}\n  \nf\ng }}\nRUN h");
+        assert_eq!(origins(&map, 9), vec![
+            Some("t:1".into()),
+            Some("t:3".into()),
+            Some("t:5".into()),
+            Some("t:5".into()),
+            None,
+            Some("t:9".into()),
+            None,
+            Some("t:11".into()),
+            Some("t:11".into()),
+        ]);
+        assert_eq!(map.origin(10).map(|o| o.line), Some(13));
+    }
+
+    #[test]
+    fn test_split_writes() {
+        let templates = &[("t", "FROM a\n{% if true %}\nRUN b\n{% endif %}\n")];
+        let marked = mark("t", templates[0].1);
+        let (output, map) = render(templates);
+
+        // Markers cut off between writes are still found
+        let mut recorder = Recorder::default();
+        let mut text = Vec::new();
+        let env = minijinja::Environment::new();
+        let rendered = env.render_str(&marked, ()).unwrap();
+        for byte in rendered.as_bytes() {
+            recorder.write(std::slice::from_ref(byte), &mut text);
+        }
+        let split = recorder.finish(&mut text);
+        assert_eq!(String::from_utf8(text).unwrap(), output);
+        assert_eq!(split.lines, map.lines);
+    }
+
+    #[test]
+    fn test_unmark() {
+        let marked = mark("t", "FROM a\n  RUN {{ b }}\n");
+        assert_ne!(marked, "FROM a\n  RUN {{ b }}\n");
+        assert_eq!(unmark(&marked), "FROM a\n  RUN {{ b }}\n");
+
+        let offset = marked.find("{{").unwrap();
+        assert_eq!(unmarked_offset(&marked, offset), "FROM a\n  RUN ".len());
+    }
+
+    #[test]
+    fn test_annotate_docker_output() {
+        let (_, map) = render(TEMPLATES);
+
+        assert_eq!(map.annotate_docker_output("Dockerfile:4\n"), "Dockerfile:4 (Dockerfile.j2:4)\n");
+        assert_eq!(
+            map.annotate_docker_output("ERROR: failed to solve: dockerfile parse error on line 2: unknown instruction"),
+            "ERROR: failed to solve: dockerfile parse error on line 2 (Dockerfile.j2:2): unknown instruction",
+        );
+        assert_eq!(map.annotate_docker_output("Dockerfile:99\n"), "Dockerfile:99\n");
+
+        // Output of the build steps is left alone
+        for output in ["#7 0.512 test.rs: failed on line 4\n", "#7 0.512 see Dockerfile:4 and Dockerfile.j2:4\n"] {
+            assert_eq!(map.annotate_docker_output(output), output);
+        }
+    }
+}
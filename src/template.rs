@@ -1,32 +1,98 @@
 
-use std::{collections::HashMap, ffi::OsStr, fmt::{self, Display as _}, fs, io::{BufReader, Read, Write}, path::Path, sync::Arc};
+use std::{collections::{BTreeMap, HashMap}, ffi::OsStr, fmt, fs, io::{self, BufReader, Read, Write}, path::{Path, PathBuf}, sync::{Arc, Mutex}};
 
 use anyhow::Context as _;
 use minijinja::{value::{DynObject, Object}, Value};
+use serde::Deserialize;
+
+use crate::{diagnostic::RenderError, hash, sourcemap, time::{self, DateTime}, version};
+
+/// How templates treat undefined variables, after minijinja's `UndefinedBehavior`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strictness {
+    /// Any use of an undefined value fails
+    Strict,
+    /// Undefined values can be tested and defaulted, but not printed or iterated
+    #[default]
+    SemiStrict,
+    /// Undefined values print as empty, but attributes of them fail
+    Lenient,
+    /// Undefined values and any attributes of them print as empty
+    Chainable,
+}
+
+impl Strictness {
+    fn undefined_behavior(self) -> minijinja::UndefinedBehavior {
+        match self {
+            Self::Strict => minijinja::UndefinedBehavior::Strict,
+            Self::SemiStrict => minijinja::UndefinedBehavior::SemiStrict,
+            Self::Lenient => minijinja::UndefinedBehavior::Lenient,
+            Self::Chainable => minijinja::UndefinedBehavior::Chainable,
+        }
+    }
+}
+
+impl fmt::Display for Strictness {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Strict => "strict",
+            Self::SemiStrict => "semi-strict",
+            Self::Lenient => "lenient",
+            Self::Chainable => "chainable",
+        })
+    }
+}
+
+/// Names of the templates loaded so far, and the files they were loaded from
+type LoadedTemplates = Arc<Mutex<BTreeMap<String, PathBuf>>>;
 
 #[derive(Debug)]
 pub struct Environment {
     environment: minijinja::Environment<'static>,
-    vars: HashMap<String, minijinja::Value>
+    vars: HashMap<String, minijinja::Value>,
+    loaded: LoadedTemplates,
 }
 
 impl Environment {
-    pub fn new<P>(root: P) -> Self
+    /// Templates are loaded from the first directory in `search_path` that has them.
+    pub fn new<P>(search_path: &[P]) -> Self
     where 
         P: AsRef<Path>,
     {
+        let loaded = LoadedTemplates::default();
         let mut environment = minijinja::Environment::empty();
-        environment.set_loader(minijinja::path_loader(root));
+        let roots = search_path.iter().map(|p| p.as_ref().to_owned()).collect();
+        environment.set_loader(path_loader(roots, loaded.clone()));
         environment.set_undefined_behavior(minijinja::UndefinedBehavior::SemiStrict);
+        // Keep template sources for error messages, even in release builds
+        environment.set_debug(true);
         register_filters(&mut environment);
         register_functions(&mut environment);
         register_tests(&mut environment);
         Self {
             environment,
             vars: HashMap::new(),
+            loaded,
         }
     }
 
+    pub fn set_strictness(&mut self, strictness: Strictness) {
+        self.environment.set_undefined_behavior(strictness.undefined_behavior());
+    }
+
+    /// Make `now()` and `utcnow()` always give this time, for reproducible renders.
+    pub fn set_now(&mut self, time: DateTime) {
+        let utc = time.utc();
+        self.environment.add_function("now", move || Value::from_object(time.clone()));
+        self.environment.add_function("utcnow", move || Value::from_object(utc.clone()));
+    }
+
+    /// Names and files of the templates loaded through `include`, `import` or `extends` so far.
+    pub fn loaded_templates(&self) -> Vec<(String, PathBuf)> {
+        self.loaded.lock().unwrap().iter().map(|(name, path)| (name.clone(), path.clone())).collect()
+    }
+
     pub fn set<V>(&mut self, name: String, value: V)
     where 
         V: Into<minijinja::Value>
@@ -34,7 +100,8 @@ impl Environment {
         self.vars.insert(name, value.into());
     }
 
-    pub fn render_to(&self, src: &Path, w: impl Write) -> anyhow::Result<()> {
+    /// Render `src`, with `extra` variables in addition to the ones that were set.
+    pub fn render_to(&self, src: &Path, extra: HashMap<String, minijinja::Value>, w: impl Write) -> anyhow::Result<()> {
         let name = src.file_name().unwrap_or_else(|| OsStr::new("<input>")).to_string_lossy();
         let mut f = BufReader::new(fs::File::open(src)
             .with_context(|| format!("Failed to read file: {}", src.display()))?);
@@ -42,59 +109,41 @@ impl Environment {
         let mut template = String::new();
         f.read_to_string(& mut template)?;
 
-        let tpl = self.environment.template_from_named_str(&name, &template)?;
-
-        tpl.render_to_write(&self.vars, w)?;
+        let mut vars = self.vars.clone();
+        vars.extend(extra);
+        self.environment.template_from_named_str(&name, &sourcemap::mark(&name, &template))
+            .and_then(|tpl| tpl.render_to_write(&vars, w).map(|_| ()))
+            .map_err(|e| RenderError::new(&e, &name, &template, |n| {
+                self.environment.get_template(n).ok().map(|t| sourcemap::unmark(t.source()).into_owned())
+            }))?;
 
         Ok(())
     }
 }
 
-#[derive(Debug)]
-struct DateTime(chrono::DateTime<chrono::Local>);
-
-impl DateTime {
-    pub fn now() -> Self {
-        Self(chrono::Local::now())
-    }
-
-    pub fn format(&self, format: &str) -> String {
-        self.0.format(format).to_string()
-    }
-
-    pub fn timestamp(&self) -> i64 {
-        self.0.timestamp()
-    }
-}
+/// Like `minijinja::path_loader`, but searches several directories and keeps track of the files it loads.
+///
+/// Templates are marked for the source map, see [`sourcemap::mark`].
+fn path_loader(roots: Vec<PathBuf>, loaded: LoadedTemplates) -> impl Fn(&str) -> Result<Option<String>, minijinja::Error> + Send + Sync + 'static {
+    move |name| {
+        let segments: Vec<_> = name.split('/').collect();
+        if segments.iter().any(|s| s.starts_with('.') || s.contains('\\')) {
+            return Ok(None);
+        }
 
-impl Object for DateTime {
-    fn call_method(
-        self: &Arc<Self>,
-        state: &minijinja::State<'_, '_>,
-        method: &str,
-        args: &[Value],
-    ) -> Result<Value, minijinja::Error> {
-        let this = self.clone();
-        let method = if method == "format" {
-            if args.is_empty() {
-                Value::from_function(move || this.format("%+"))
-            } else {
-                Value::from_function(move |s: &str| this.format(s))
+        for root in &roots {
+            let path: PathBuf = std::iter::once(root.as_os_str()).chain(segments.iter().map(OsStr::new)).collect();
+            match fs::read_to_string(&path) {
+                Ok(source) => {
+                    loaded.lock().unwrap().insert(name.to_owned(), path);
+                    return Ok(Some(sourcemap::mark(name, &source)));
+                },
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(minijinja::Error::new(minijinja::ErrorKind::InvalidOperation, "could not read template").with_source(e)),
             }
-        } else if method == "timestamp" {
-            Value::from_function(move || this.timestamp())
-        } else {
-            return Err(minijinja::Error::from(minijinja::ErrorKind::UnknownMethod))
-        };
-
-        method.call(state, args)
-    }
+        }
 
-    fn render(self: &Arc<Self>, f: &mut fmt::Formatter<'_>) -> fmt::Result
-    where
-        Self: Sized + 'static,
-    {
-        self.0.fmt(f)
+        Ok(None)
     }
 }
 
@@ -126,6 +175,24 @@ impl<T: Object + 'static> IntoValue for Arc<T> {
     }
 }
 
+impl IntoValue for String {
+    fn into_value(self) -> Result<Value, minijinja::Error> {
+        Ok(self.into())
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self) -> Result<Value, minijinja::Error> {
+        Ok(self.into())
+    }
+}
+
+impl IntoValue for Vec<String> {
+    fn into_value(self) -> Result<Value, minijinja::Error> {
+        Ok(self.into())
+    }
+}
+
 impl IntoValue for () {
     fn into_value(self) -> Result<Value, minijinja::Error> {
         Ok(().into())
@@ -140,8 +207,13 @@ fn register_filters(env: &mut minijinja::Environment) {
     env.add_filter("batch", batch);
     env.add_filter("bool", bool);
     env.add_filter("capitalize", capitalize);
+    env.add_filter("chain", chain);
+    env.add_filter("count", length);
+    env.add_filter("d", default);
     env.add_filter("default", default);
     env.add_filter("dictsort", dictsort);
+    env.add_filter("e", escape);
+    env.add_filter("escape", escape);
     env.add_filter("first", first);
     env.add_filter("float", float);
     env.add_filter("groupby", groupby);
@@ -163,16 +235,76 @@ fn register_filters(env: &mut minijinja::Environment) {
     env.add_filter("replace", replace);
     env.add_filter("reverse", reverse);
     env.add_filter("round", round);
+    env.add_filter("safe", safe);
     env.add_filter("select", select);
     env.add_filter("selectattr", selectattr);
     env.add_filter("slice", slice);
     env.add_filter("sort", sort);
     env.add_filter("split", split);
     env.add_filter("string", string);
+    env.add_filter("sum", sum);
+    env.add_filter("title", title);
     env.add_filter("tojson", tojson);
     env.add_filter("trim", trim);
     env.add_filter("unique", unique);
-    env.add_filter("upper", unique);   
+    env.add_filter("upper", upper);
+    env.add_filter("urlencode", urlencode);
+
+    // Dockerfile specific
+    env.add_filter("exec_form", exec_form);
+    env.add_filter("heredoc_safe", heredoc_safe);
+    env.add_filter("shquote", shquote);
+
+    env.add_filter("sha256", sha256);
+}
+
+/// Hex SHA-256 digest of a string.
+fn sha256(value: &str) -> String {
+    hash::digest(hash::Algo::Sha256, value.as_bytes())
+}
+
+/// Quote a string (or each item of a list, joined by spaces) for a POSIX shell.
+fn shquote(value: &Value) -> Result<String, minijinja::Error> {
+    fn quote(s: &str) -> String {
+        let plain = !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c));
+        if plain {
+            s.to_owned()
+        } else {
+            format!("'{}'", s.replace('\'', r"'\''"))
+        }
+    }
+
+    if value.kind() == minijinja::value::ValueKind::Seq {
+        let items: Vec<_> = value.try_iter()?.map(|v| quote(&v.to_string())).collect();
+        Ok(items.join(" "))
+    } else {
+        Ok(quote(&value.to_string()))
+    }
+}
+
+/// Turn a list (or a string, split like a shell would) into the JSON array of the exec
+/// form of `CMD`, `ENTRYPOINT` and `RUN`.
+fn exec_form(value: &Value) -> Result<String, minijinja::Error> {
+    let args: Vec<String> = if let Some(s) = value.as_str() {
+        shlex::split(s).ok_or_else(|| minijinja::Error::new(minijinja::ErrorKind::InvalidOperation, format!("Can't split {s:?} into arguments")))?
+    } else {
+        value.try_iter()?.map(|v| v.to_string()).collect()
+    };
+
+    serde_json::to_string(&args).map_err(|e| minijinja::Error::new(minijinja::ErrorKind::InvalidOperation, "Can't write exec form").with_source(e))
+}
+
+/// Check that content can go inside a heredoc without ending it early.
+fn heredoc_safe(content: &str, delimiter: Option<&str>) -> Result<String, minijinja::Error> {
+    let delimiter = delimiter.unwrap_or("EOF");
+    // `<<-` heredocs strip leading tabs before looking for the delimiter
+    if content.lines().any(|l| l.trim_start_matches('\t') == delimiter) {
+        return Err(minijinja::Error::new(
+            minijinja::ErrorKind::InvalidOperation,
+            format!("Content contains a line with just the heredoc delimiter {delimiter}, use a different delimiter"),
+        ));
+    }
+    Ok(content.to_owned())
 }
 
 fn register_functions(env: &mut minijinja::Environment) {
@@ -183,6 +315,12 @@ fn register_functions(env: &mut minijinja::Environment) {
     env.add_function("namespace", namespace);
     env.add_function("range", range);
     env.add_function("now", || Value::from_object(DateTime::now()));
+    env.add_function("utcnow", || Value::from_object(DateTime::utcnow()));
+    env.add_function("parse_datetime", time::parse_datetime);
+    env.add_function("semver", version::semver);
+    env.add_function("bump_major", version::bump_major);
+    env.add_function("bump_minor", version::bump_minor);
+    env.add_function("bump_patch", version::bump_patch);
 }
 
 fn register_tests(env: &mut minijinja::Environment) {
@@ -193,23 +331,36 @@ fn register_tests(env: &mut minijinja::Environment) {
     env.add_test("divisibleby", is_divisibleby);
     env.add_test("endingwith", is_endingwith);
     env.add_test("eq", is_eq);
+    env.add_test("equalto", is_eq);
+    env.add_test("==", is_eq);
+    env.add_test("escaped", is_safe);
     env.add_test("even", is_even);
     env.add_test("false", is_false);
     env.add_test("filter", is_filter);
     env.add_test("float", is_float);
     env.add_test("ge", is_ge);
+    env.add_test(">=", is_ge);
+    env.add_test("greaterthan", is_gt);
     env.add_test("gt", is_gt);
+    env.add_test(">", is_gt);
     env.add_test("in", is_in);
+    env.add_test("int", is_integer);
     env.add_test("integer", is_integer);
     env.add_test("iterable", is_iterable);
     env.add_test("le", is_le);
+    env.add_test("<=", is_le);
+    env.add_test("lessthan", is_lt);
     env.add_test("lower", is_lower);
     env.add_test("lt", is_lt);
+    env.add_test("<", is_lt);
     env.add_test("mapping", is_mapping);
     env.add_test("ne", is_ne);
+    env.add_test("!=", is_ne);
     env.add_test("none", is_none);
     env.add_test("number", is_number);
     env.add_test("odd", is_odd);
+    env.add_test("safe", is_safe);
+    env.add_test("sameas", is_sameas);
     env.add_test("sequence", is_sequence);
     env.add_test("startingwith", is_startingwith);
     env.add_test("string", is_string);
@@ -217,4 +368,39 @@ fn register_tests(env: &mut minijinja::Environment) {
     env.add_test("true", is_true);
     env.add_test("undefined", is_undefined);
     env.add_test("upper", is_upper);
+    env.add_test("version_eq", version::is_version_eq);
+    env.add_test("version_ge", version::is_version_ge);
+    env.add_test("version_gt", version::is_version_gt);
+    env.add_test("version_le", version::is_version_le);
+    env.add_test("version_lt", version::is_version_lt);
+    env.add_test("version_matches", version::is_version_matches);
+    env.add_test("version_ne", version::is_version_ne);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shquote() {
+        assert_eq!(shquote(&Value::from("abc/def-1.2")).unwrap(), "abc/def-1.2");
+        assert_eq!(shquote(&Value::from("")).unwrap(), "''");
+        assert_eq!(shquote(&Value::from("it's $HOME")).unwrap(), r"'it'\''s $HOME'");
+        assert_eq!(shquote(&Value::from(vec!["a b", "c"])).unwrap(), "'a b' c");
+    }
+
+    #[test]
+    fn test_exec_form() {
+        assert_eq!(exec_form(&Value::from(vec!["app", "--name", "a \"b\""])).unwrap(), r#"["app","--name","a \"b\""]"#);
+        assert_eq!(exec_form(&Value::from("app --name 'a b'")).unwrap(), r#"["app","--name","a b"]"#);
+        assert!(exec_form(&Value::from("app 'a")).is_err());
+    }
+
+    #[test]
+    fn test_heredoc_safe() {
+        assert_eq!(heredoc_safe("a\nEOFX\n", None).unwrap(), "a\nEOFX\n");
+        assert!(heredoc_safe("a\nEOF\nb", None).is_err());
+        assert!(heredoc_safe("a\n\tEND", Some("END")).is_err());
+        assert!(heredoc_safe("a\nEOF", Some("END")).is_ok());
+    }
 }
@@ -0,0 +1,197 @@
+use std::{fmt::{self, Display as _}, sync::Arc};
+
+use anyhow::{anyhow, Context as _};
+use chrono::{Datelike as _, FixedOffset, NaiveDate, NaiveDateTime, TimeDelta, Timelike as _, Utc};
+use minijinja::{value::Object, Error, ErrorKind, Value};
+
+/// A point in time with its offset, as given to templates by `now()` and friends.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DateTime(chrono::DateTime<FixedOffset>);
+
+impl DateTime {
+    pub fn now() -> Self {
+        Self(chrono::Local::now().fixed_offset())
+    }
+
+    pub fn utcnow() -> Self {
+        Self(Utc::now().fixed_offset())
+    }
+
+    /// A unix timestamp, in UTC.
+    pub fn from_timestamp(timestamp: i64) -> Option<Self> {
+        Some(Self(chrono::DateTime::from_timestamp(timestamp, 0)?.fixed_offset()))
+    }
+
+    /// Parse RFC 3339, `YYYY-MM-DD HH:MM:SS` or `YYYY-MM-DD` (taken as UTC), or a unix
+    /// timestamp, or parse with a strftime format if one is given.
+    pub fn parse(s: &str, format: Option<&str>) -> anyhow::Result<Self> {
+        let utc = |naive: NaiveDateTime| Self(naive.and_utc().fixed_offset());
+
+        let parsed = if let Some(format) = format {
+            chrono::DateTime::parse_from_str(s, format).map(Self)
+                .or_else(|_| NaiveDateTime::parse_from_str(s, format).map(utc))
+                .or_else(|_| NaiveDate::parse_from_str(s, format).map(|d| utc(d.into())))
+                .ok()
+        } else {
+            chrono::DateTime::parse_from_rfc3339(s).map(Self)
+                .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").map(utc))
+                .or_else(|_| NaiveDate::parse_from_str(s, "%Y-%m-%d").map(|d| utc(d.into())))
+                .ok()
+                .or_else(|| Self::from_timestamp(s.parse().ok()?))
+        };
+
+        parsed.ok_or_else(|| anyhow!("Invalid date and time {s:?}"))
+    }
+
+    pub fn format(&self, format: &str) -> String {
+        self.0.format(format).to_string()
+    }
+
+    pub fn timestamp(&self) -> i64 {
+        self.0.timestamp()
+    }
+
+    pub fn rfc3339(&self) -> String {
+        self.0.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true)
+    }
+
+    /// ISO 8601 week, such as `2026-W42`.
+    pub fn iso_week(&self) -> String {
+        let week = self.0.iso_week();
+        format!("{}-W{:02}", week.year(), week.week())
+    }
+
+    pub fn utc(&self) -> Self {
+        Self(self.0.to_utc().fixed_offset())
+    }
+
+    pub fn local(&self) -> Self {
+        Self(self.0.with_timezone(&chrono::Local).fixed_offset())
+    }
+
+    fn add(&self, delta: Option<TimeDelta>) -> Result<Self, Error> {
+        delta.and_then(|d| self.0.checked_add_signed(d))
+            .map(Self)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidOperation, "Date and time out of range"))
+    }
+}
+
+impl Object for DateTime {
+    fn call_method(
+        self: &Arc<Self>,
+        state: &minijinja::State<'_, '_>,
+        method: &str,
+        args: &[Value],
+    ) -> Result<Value, Error> {
+        let this = self.clone();
+        let method = if method == "format" {
+            if args.is_empty() {
+                Value::from_function(move || this.format("%+"))
+            } else {
+                Value::from_function(move |s: &str| this.format(s))
+            }
+        } else if method == "timestamp" {
+            Value::from_function(move || this.timestamp())
+        } else if method == "rfc3339" {
+            Value::from_function(move || this.rfc3339())
+        } else if method == "iso_week" {
+            Value::from_function(move || this.iso_week())
+        } else if method == "utc" {
+            Value::from_function(move || Value::from_object(this.utc()))
+        } else if method == "local" {
+            Value::from_function(move || Value::from_object(this.local()))
+        } else if let Some(unit) = method.strip_prefix("add_") {
+            let delta: fn(i64) -> Option<TimeDelta> = match unit {
+                "weeks" => TimeDelta::try_weeks,
+                "days" => TimeDelta::try_days,
+                "hours" => TimeDelta::try_hours,
+                "minutes" => TimeDelta::try_minutes,
+                "seconds" => TimeDelta::try_seconds,
+                _ => return Err(Error::from(ErrorKind::UnknownMethod)),
+            };
+            Value::from_function(move |n: i64| this.add(delta(n)).map(Value::from_object))
+        } else {
+            return Err(Error::from(ErrorKind::UnknownMethod))
+        };
+
+        method.call(state, args)
+    }
+
+    fn get_value(self: &Arc<Self>, key: &Value) -> Option<Value> {
+        let value = match key.as_str()? {
+            "year" => self.0.year(),
+            "month" => self.0.month() as i32,
+            "day" => self.0.day() as i32,
+            "hour" => self.0.hour() as i32,
+            "minute" => self.0.minute() as i32,
+            "second" => self.0.second() as i32,
+            // Monday is 1, as in ISO 8601
+            "weekday" => self.0.weekday().number_from_monday() as i32,
+            "week" => self.0.iso_week().week() as i32,
+            _ => return None,
+        };
+        Some(Value::from(value))
+    }
+
+    fn render(self: &Arc<Self>, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    where
+        Self: Sized + 'static,
+    {
+        self.0.fmt(f)
+    }
+}
+
+/// Parse the `--timestamp` flag (or `SOURCE_DATE_EPOCH`) into a unix timestamp.
+pub fn parse_timestamp(s: &str) -> anyhow::Result<i64> {
+    let s = s.trim();
+    let timestamp = match s.parse() {
+        Ok(timestamp) => timestamp,
+        Err(_) => chrono::DateTime::parse_from_rfc3339(s)
+            .map(|t| t.timestamp())
+            .with_context(|| format!("Invalid timestamp {s:?}, expected seconds since the epoch or RFC 3339"))?,
+    };
+    DateTime::from_timestamp(timestamp).with_context(|| format!("Timestamp {s} is out of range"))?;
+    Ok(timestamp)
+}
+
+/// `parse_datetime(s, format=none)` in templates.
+pub fn parse_datetime(s: &str, format: Option<&str>) -> Result<Value, Error> {
+    DateTime::parse(s, format)
+        .map(Value::from_object)
+        .map_err(|e| Error::new(ErrorKind::InvalidOperation, e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let expected = DateTime::from_timestamp(1_700_000_000).unwrap();
+        assert_eq!(DateTime::parse("2023-11-14T22:13:20Z", None).unwrap(), expected);
+        assert_eq!(DateTime::parse("2023-11-14 22:13:20", None).unwrap(), expected);
+        assert_eq!(DateTime::parse("1700000000", None).unwrap(), expected);
+        assert_eq!(DateTime::parse("14/11/2023 22:13", Some("%d/%m/%Y %H:%M")).unwrap().timestamp(), 1_700_000_000 - 20);
+        assert_eq!(DateTime::parse("2023-11-14", None).unwrap().rfc3339(), "2023-11-14T00:00:00Z");
+        assert_eq!(DateTime::parse("2023-11-15T01:13:20+03:00", None).unwrap().timestamp(), 1_700_000_000);
+        assert!(DateTime::parse("yesterday", None).is_err());
+    }
+
+    #[test]
+    fn test_format() {
+        let time = DateTime::from_timestamp(1_700_000_000).unwrap();
+        assert_eq!(time.rfc3339(), "2023-11-14T22:13:20Z");
+        assert_eq!(time.iso_week(), "2023-W46");
+        // The ISO week year differs from the calendar year around new year
+        assert_eq!(DateTime::parse("2027-01-01", None).unwrap().iso_week(), "2026-W53");
+        assert_eq!(time.add(TimeDelta::try_days(-14)).unwrap().format("%F"), "2023-10-31");
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(parse_timestamp("1700000000\n").unwrap(), 1_700_000_000);
+        assert_eq!(parse_timestamp("2023-11-14T22:13:20Z").unwrap(), 1_700_000_000);
+        assert!(parse_timestamp("soon").is_err());
+        assert!(parse_timestamp(&i64::MAX.to_string()).is_err());
+    }
+}
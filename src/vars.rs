@@ -0,0 +1,66 @@
+use std::{fs, path::{Path, PathBuf}};
+
+use anyhow::{bail, Context as _};
+use serde_json::Value;
+
+/// Load variables files, merging each one into the previous ones.
+///
+/// Maps are merged key by key, anything else (including lists) is replaced.
+pub fn load(paths: &[PathBuf]) -> anyhow::Result<Value> {
+    let mut vars = Value::Object(Default::default());
+    for path in paths {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read vars file: {}", path.display()))?;
+        let value = parse(path, &contents)
+            .with_context(|| format!("Failed to parse vars file: {}", path.display()))?;
+        merge(&mut vars, value);
+    }
+    Ok(vars)
+}
+
+fn parse(path: &Path, contents: &str) -> anyhow::Result<Value> {
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default();
+    let value = match extension.to_ascii_lowercase().as_str() {
+        "toml" => toml::from_str(contents)?,
+        "yaml" | "yml" => serde_norway::from_str(contents)?,
+        "json" => serde_json::from_str(contents)?,
+        _ => bail!("Unknown format, expected a .toml, .yaml, .yml or .json file"),
+    };
+    Ok(value)
+}
+
+fn merge(dest: &mut Value, src: Value) {
+    match (dest, src) {
+        (Value::Object(dest), Value::Object(src)) => {
+            for (key, value) in src {
+                match dest.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => { dest.insert(key, value); },
+                }
+            }
+        },
+        (dest, src) => *dest = src,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_merge() {
+        let mut vars = json!({ "services": ["a", "b"], "versions": { "rust": "1.88", "node": "22" } });
+        merge(&mut vars, json!({ "services": ["c"], "versions": { "rust": "1.89" } }));
+        assert_eq!(vars, json!({ "services": ["c"], "versions": { "rust": "1.89", "node": "22" } }));
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse(Path::new("a.toml"), "a = [1, 2]").unwrap(), json!({ "a": [1, 2] }));
+        assert_eq!(parse(Path::new("a.yml"), "a:\n  - 1\n  - 2\n").unwrap(), json!({ "a": [1, 2] }));
+        assert_eq!(parse(Path::new("a.json"), r#"{"a": [1, 2]}"#).unwrap(), json!({ "a": [1, 2] }));
+        assert!(parse(Path::new("a.ini"), "").is_err());
+    }
+}
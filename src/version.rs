@@ -0,0 +1,203 @@
+use std::{cmp::Ordering, fmt, sync::Arc};
+
+use minijinja::{value::Object, Error, ErrorKind, Value};
+
+/// A semantic version in templates, from `semver(s)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version(semver::Version);
+
+impl Version {
+    /// Parse a version leniently: a leading `v` is skipped and a missing minor or
+    /// patch number is taken to be 0, so that `v1.88` reads as `1.88.0`.
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let trimmed = s.trim();
+        let trimmed = trimmed.strip_prefix(['v', 'V']).unwrap_or(trimmed);
+
+        let end = trimmed.find(['-', '+']).unwrap_or(trimmed.len());
+        let (core, rest) = trimmed.split_at(end);
+        let padding = match core.split('.').count() {
+            1 => ".0.0",
+            2 => ".0",
+            _ => "",
+        };
+
+        semver::Version::parse(&format!("{core}{padding}{rest}"))
+            .map(Self)
+            .map_err(|e| Error::new(ErrorKind::InvalidOperation, format!("Invalid version {s:?}")).with_source(e))
+    }
+
+    /// A version from a template value, either a version object or a string.
+    fn from_value(value: &Value) -> Result<Self, Error> {
+        match value.downcast_object_ref::<Self>() {
+            Some(version) => Ok(version.clone()),
+            None => Self::parse(&value.to_string()),
+        }
+    }
+
+    /// The next release, which for a prerelease whose lower parts are already zero is
+    /// the release itself, so that `1.2.3-rc.1` bumps to `1.2.3`, as npm and cargo do.
+    fn bump(&self, part: Part) -> Self {
+        let v = &self.0;
+        let pre = !v.pre.is_empty();
+        let (major, minor, patch) = match part {
+            Part::Major if pre && v.minor == 0 && v.patch == 0 => (v.major, 0, 0),
+            Part::Major => (v.major + 1, 0, 0),
+            Part::Minor if pre && v.patch == 0 => (v.major, v.minor, 0),
+            Part::Minor => (v.major, v.minor + 1, 0),
+            Part::Patch if pre => (v.major, v.minor, v.patch),
+            Part::Patch => (v.major, v.minor, v.patch + 1),
+        };
+        Self(semver::Version::new(major, minor, patch))
+    }
+
+    fn matches(&self, req: &str) -> Result<bool, Error> {
+        let req = semver::VersionReq::parse(req)
+            .map_err(|e| Error::new(ErrorKind::InvalidOperation, format!("Invalid version requirement {req:?}")).with_source(e))?;
+        Ok(req.matches(&self.0))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Part {
+    Major,
+    Minor,
+    Patch,
+}
+
+impl Object for Version {
+    fn get_value(self: &Arc<Self>, key: &Value) -> Option<Value> {
+        let v = &self.0;
+        let value = match key.as_str()? {
+            "major" => Value::from(v.major),
+            "minor" => Value::from(v.minor),
+            "patch" => Value::from(v.patch),
+            "pre" => Value::from(v.pre.as_str()),
+            "build" => Value::from(v.build.as_str()),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    fn call_method(
+        self: &Arc<Self>,
+        state: &minijinja::State<'_, '_>,
+        method: &str,
+        args: &[Value],
+    ) -> Result<Value, Error> {
+        let this = self.clone();
+        let method = if method == "bump_major" {
+            Value::from_function(move || Value::from_object(this.bump(Part::Major)))
+        } else if method == "bump_minor" {
+            Value::from_function(move || Value::from_object(this.bump(Part::Minor)))
+        } else if method == "bump_patch" {
+            Value::from_function(move || Value::from_object(this.bump(Part::Patch)))
+        } else if method == "matches" {
+            Value::from_function(move |req: &str| this.matches(req))
+        } else {
+            return Err(Error::from(ErrorKind::UnknownMethod))
+        };
+
+        method.call(state, args)
+    }
+
+    fn render(self: &Arc<Self>, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+pub fn semver(s: &Value) -> Result<Value, Error> {
+    Version::from_value(s).map(Value::from_object)
+}
+
+pub fn bump_major(v: &Value) -> Result<Value, Error> {
+    Ok(Value::from_object(Version::from_value(v)?.bump(Part::Major)))
+}
+
+pub fn bump_minor(v: &Value) -> Result<Value, Error> {
+    Ok(Value::from_object(Version::from_value(v)?.bump(Part::Minor)))
+}
+
+pub fn bump_patch(v: &Value) -> Result<Value, Error> {
+    Ok(Value::from_object(Version::from_value(v)?.bump(Part::Patch)))
+}
+
+/// Compare by SemVer precedence, which ignores build metadata.
+fn compare(a: &Value, b: &Value) -> Result<Ordering, Error> {
+    Ok(Version::from_value(a)?.0.cmp_precedence(&Version::from_value(b)?.0))
+}
+
+pub fn is_version_eq(a: &Value, b: &Value) -> Result<bool, Error> {
+    Ok(compare(a, b)?.is_eq())
+}
+
+pub fn is_version_ne(a: &Value, b: &Value) -> Result<bool, Error> {
+    Ok(compare(a, b)?.is_ne())
+}
+
+pub fn is_version_gt(a: &Value, b: &Value) -> Result<bool, Error> {
+    Ok(compare(a, b)?.is_gt())
+}
+
+pub fn is_version_ge(a: &Value, b: &Value) -> Result<bool, Error> {
+    Ok(compare(a, b)?.is_ge())
+}
+
+pub fn is_version_lt(a: &Value, b: &Value) -> Result<bool, Error> {
+    Ok(compare(a, b)?.is_lt())
+}
+
+pub fn is_version_le(a: &Value, b: &Value) -> Result<bool, Error> {
+    Ok(compare(a, b)?.is_le())
+}
+
+pub fn is_version_matches(v: &Value, req: &str) -> Result<bool, Error> {
+    Version::from_value(v)?.matches(req)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Version::parse("1.88").unwrap().0, semver::Version::new(1, 88, 0));
+        assert_eq!(Version::parse("v2").unwrap().0, semver::Version::new(2, 0, 0));
+        assert_eq!(Version::parse("1.2.3-rc.1+abc").unwrap().0.to_string(), "1.2.3-rc.1+abc");
+        assert_eq!(Version::parse("1.2-beta").unwrap().0.to_string(), "1.2.0-beta");
+        assert!(Version::parse("latest").is_err());
+    }
+
+    #[test]
+    fn test_compare() {
+        // As strings, "1.9" > "1.10"
+        assert!(is_version_lt(&Value::from("1.9"), &Value::from("1.10")).unwrap());
+        assert!(is_version_ge(&Value::from("1.10.0"), &Value::from("1.10")).unwrap());
+        assert!(is_version_lt(&Value::from("2.0.0-rc.1"), &Value::from("2.0.0")).unwrap());
+        assert!(is_version_matches(&Value::from("1.88"), ">=1.80, <2").unwrap());
+    }
+
+    #[test]
+    fn test_compare_build_metadata() {
+        let (a, b) = (Value::from("1.0.0+a"), Value::from("1.0.0+b"));
+        assert!(is_version_eq(&a, &b).unwrap());
+        assert!(!is_version_ne(&a, &b).unwrap());
+        assert!(!is_version_gt(&b, &a).unwrap());
+        assert!(is_version_le(&b, &a).unwrap());
+        assert!(is_version_lt(&Value::from("1.0.0-rc.1+b"), &a).unwrap());
+    }
+
+    #[test]
+    fn test_bump() {
+        let v = Version::parse("1.2.3-rc.1").unwrap();
+        assert_eq!(v.bump(Part::Major).0.to_string(), "2.0.0");
+        assert_eq!(v.bump(Part::Minor).0.to_string(), "1.3.0");
+        assert_eq!(v.bump(Part::Patch).0.to_string(), "1.2.3");
+
+        let v = Version::parse("1.2.3").unwrap();
+        assert_eq!(v.bump(Part::Patch).0.to_string(), "1.2.4");
+        assert_eq!(Version::parse("1.2.0-rc.1").unwrap().bump(Part::Minor).0.to_string(), "1.2.0");
+        assert_eq!(Version::parse("1.2.0-rc.1").unwrap().bump(Part::Major).0.to_string(), "2.0.0");
+        assert_eq!(Version::parse("2.0.0-rc.1").unwrap().bump(Part::Major).0.to_string(), "2.0.0");
+        assert_eq!(Version::parse("2.0.0-rc.1+b").unwrap().bump(Part::Minor).0.to_string(), "2.0.0");
+    }
+}
@@ -0,0 +1,202 @@
+use std::{collections::BTreeMap, fs, path::{Path, PathBuf}, thread, time::{Duration, SystemTime}};
+
+use crate::{build::{BernBuild, BernConfig}, context::{self, DockerIgnore}};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+type Snapshot = BTreeMap<PathBuf, Option<(SystemTime, u64)>>;
+
+/// Files that affect a build: the template, anything it included, and the context.
+struct Watched {
+    root: PathBuf,
+    files: Vec<PathBuf>,
+    skip: Option<PathBuf>,
+}
+
+impl Watched {
+    /// The files known before building: the template, the vars files and the context.
+    fn before_build(config: &BernConfig) -> Self {
+        let mut files = vec![config.file.clone()];
+        files.extend(config.vars_files.iter().cloned());
+
+        Self {
+            root: config.context_root.clone(),
+            files,
+            skip: None,
+        }
+    }
+
+    fn new(config: &BernConfig, build: &BernBuild) -> Self {
+        let mut watched = Self::before_build(config);
+        watched.files.extend(build.loaded_templates().into_iter().map(|(_, path)| path));
+        // Don't rebuild because the build wrote its own output
+        watched.skip = build.output().and_then(|o| o.canonicalize().ok());
+        watched
+    }
+
+    fn skipped(&self, path: &Path) -> bool {
+        self.skip.as_ref().is_some_and(|s| path.starts_with(s))
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        let mut snapshot: Snapshot = self.files.iter().map(|f| (f.clone(), stat(f))).collect();
+
+        let root = self.root.canonicalize().unwrap_or_else(|_| self.root.clone());
+        let ignore = DockerIgnore::read(&root);
+        for entry in context::walk(&root, &ignore).filter_map(Result::ok) {
+            let path = entry.path();
+            if self.skipped(path) || entry.file_type().is_dir() {
+                continue;
+            }
+            snapshot.insert(path.to_owned(), stat(path));
+        }
+
+        snapshot
+    }
+
+    /// A snapshot taken before building, with what only the build knew about filled in:
+    /// templates it included are taken as they are now, and its own output is left out.
+    fn baseline(&self, mut initial: Snapshot) -> Snapshot {
+        initial.retain(|path, _| !self.skipped(path));
+        for file in &self.files {
+            initial.entry(file.clone()).or_insert_with(|| stat(file));
+        }
+        initial
+    }
+
+    /// Wait until something changes since the `initial` snapshot, taken before building,
+    /// and then until changes settle down.
+    fn wait(&self, initial: Snapshot) {
+        let initial = self.baseline(initial);
+        let mut current = loop {
+            thread::sleep(POLL_INTERVAL);
+            let current = self.snapshot();
+            if current != initial {
+                break current;
+            }
+        };
+
+        loop {
+            thread::sleep(DEBOUNCE);
+            let next = self.snapshot();
+            if next == current {
+                break;
+            }
+            current = next;
+        }
+    }
+}
+
+fn stat(path: &Path) -> Option<(SystemTime, u64)> {
+    let meta = fs::metadata(path).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+/// Run `action` on a fresh build every time the template or context changes.
+///
+/// Errors from the action are reported, and then the next change is waited for as usual.
+pub fn watch<F>(config: &BernConfig, mut action: F) -> !
+where
+    F: FnMut(&BernBuild) -> anyhow::Result<()>,
+{
+    loop {
+        // Taken first, so that changes made during the build cause another one
+        let initial = Watched::before_build(config).snapshot();
+        let build = BernBuild::new(config.clone());
+        if let Err(e) = action(&build) {
+            eprintln!("Error: {e:?}");
+        }
+
+        eprintln!("[Bern] Watching for changes...");
+        Watched::new(config, &build).wait(initial);
+        eprintln!("[Bern] Changes detected");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("context");
+        let template = dir.path().join("Dockerfile.j2");
+        fs::create_dir_all(root.join("target")).unwrap();
+        fs::create_dir_all(root.join("out")).unwrap();
+        fs::write(root.join(".dockerignore"), "target\n").unwrap();
+        fs::write(root.join("main.rs"), "a").unwrap();
+        fs::write(&template, "FROM scratch").unwrap();
+
+        let watched = Watched {
+            root: root.clone(),
+            files: vec![template.clone()],
+            skip: Some(root.join("out").canonicalize().unwrap()),
+        };
+        let initial = watched.snapshot();
+
+        // Ignored files and the build's own output don't count
+        fs::write(root.join("target/bern"), "a").unwrap();
+        fs::write(root.join("out/result"), "a").unwrap();
+        assert_eq!(watched.snapshot(), initial);
+
+        fs::write(root.join("main.rs"), "ab").unwrap();
+        let changed = watched.snapshot();
+        assert_ne!(changed, initial);
+
+        fs::write(&template, "FROM scratch AS a").unwrap();
+        let changed_template = watched.snapshot();
+        assert_ne!(changed_template, changed);
+
+        fs::write(root.join("lib.rs"), "").unwrap();
+        assert_ne!(watched.snapshot(), changed_template);
+    }
+
+    #[test]
+    fn test_wait() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("main.rs");
+        fs::write(&file, "a").unwrap();
+
+        let watched = Watched { root: dir.path().to_owned(), files: Vec::new(), skip: None };
+        let initial = watched.snapshot();
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            fs::write(&file, "ab").unwrap();
+        });
+
+        // Returns once the change is seen and nothing else changes
+        watched.wait(initial);
+        writer.join().unwrap();
+        assert_eq!(watched.snapshot().values().filter_map(|s| s.map(|s| s.1)).collect::<Vec<_>>(), vec![2]);
+
+        // Changes made while building count too
+        let initial = watched.snapshot();
+        fs::write(dir.path().join("main.rs"), "abc").unwrap();
+        watched.wait(initial);
+    }
+
+    #[test]
+    fn test_baseline() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::create_dir_all(root.join("out")).unwrap();
+        fs::write(root.join("main.rs"), "a").unwrap();
+        fs::write(root.join("out/result"), "a").unwrap();
+
+        let before = Watched { root: root.clone(), files: Vec::new(), skip: None };
+        let initial = before.snapshot();
+
+        // The build included a template, and wrote its output
+        let included = dir.path().join("included.j2");
+        fs::write(&included, "FROM scratch").unwrap();
+        fs::write(root.join("out/result"), "ab").unwrap();
+
+        let after = Watched { root: root.clone(), files: vec![included], skip: Some(root.join("out")) };
+        assert_eq!(after.baseline(initial.clone()), after.snapshot());
+
+        fs::write(root.join("main.rs"), "ab").unwrap();
+        assert_ne!(after.baseline(initial), after.snapshot());
+    }
+}
@@ -15,15 +15,22 @@ struct TestData {
 }
 
 #[derive(Default, Debug, Deserialize)]
+#[serde(default)]
 struct TestSetup {
     files: Vec<PathBuf>,
+    contents: HashMap<PathBuf, String>,
+    /// Commands run in order after the files are in place, such as `git init`
+    commands: Vec<Vec<String>>,
 }
 
 #[derive(Debug, Default, Deserialize)]
 #[serde(default)]
 struct TestRun {
     args: Vec<String>,
+    env: HashMap<String, String>,
     status_code: i32,
+    stdout_contains: Vec<String>,
+    stdout_not_contains: Vec<String>,
     stderr_contains: Vec<String>,
     verify_files: HashMap<PathBuf, TestFileVerify>,
 }
@@ -34,6 +41,9 @@ enum TestFileVerify {
     Content(String),
 }
 
+/// Environment variables that change what bern does, unless a test run sets them.
+const CALLER_ENV: &[&str] = &["BERN_PROFILE", "BERN_BUILD_ID", "BERN_BUILDX_DEBUG", "SOURCE_DATE_EPOCH", "DOCKER"];
+
 fn show_file(path: &Path) {
     let mut f = match std::fs::File::open(path) {
         Ok(f) => f,
@@ -80,6 +90,17 @@ fn main(
         std::fs::copy(parent.join(add_file), temp_dir.path().join(add_file)).unwrap();
     }
 
+    for (add_file, contents) in &test.setup.contents {
+        let dest = temp_dir.path().join(add_file);
+        std::fs::create_dir_all(dest.parent().unwrap()).unwrap();
+        std::fs::write(dest, contents).unwrap();
+    }
+
+    for args in &test.setup.commands {
+        let status = Command::new(&args[0]).args(&args[1..]).current_dir(temp_dir.path()).status().unwrap();
+        assert!(status.success(), "Setup command {args:?} failed");
+    }
+
     if test.run.is_empty() {
         panic!("No test runs defined");
     }
@@ -88,8 +109,14 @@ fn main(
         eprintln!("--- {idx}");
 
         let mut command = Command::cargo_bin("bern").unwrap();
-        command.args(&run.args);
         command.args(&auto_args);
+        command.args(&run.args);
+        // Keep the caller's settings out of the results
+        for name in CALLER_ENV {
+            command.env_remove(name);
+        }
+        command.env("XDG_CONFIG_HOME", temp_dir.path().join("no-config"));
+        command.envs(&run.env);
         command.current_dir(temp_dir.path());
         let mut cmd_assert = command.assert();
         
@@ -100,6 +127,12 @@ fn main(
         eprintln!("Files: {}", files.join(", "));
 
         cmd_assert = cmd_assert.code(predicate::eq(run.status_code));
+        for s in &run.stdout_contains {
+            cmd_assert = cmd_assert.stdout(predicate::str::contains(s));
+        }
+        for s in &run.stdout_not_contains {
+            cmd_assert = cmd_assert.stdout(predicate::str::contains(s).not());
+        }
         for s in &run.stderr_contains {
             cmd_assert = cmd_assert.stderr(predicate::str::contains(s));
         }